@@ -0,0 +1,118 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+/// How often the key file is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// API keys accepted as Bearer tokens: the static `IMESSAGE_API_KEY` plus any
+/// listed in `IMESSAGE_API_KEYS_FILE`, which is watched so keys can be added
+/// or revoked without a restart.
+pub struct ApiKeys {
+    env_key: Option<String>,
+    file: Option<PathBuf>,
+    file_keys: RwLock<HashSet<String>>,
+}
+
+impl ApiKeys {
+    pub fn load(env_key: Option<String>, file: Option<PathBuf>) -> anyhow::Result<Self> {
+        let file_keys = match &file {
+            Some(path) => parse(&std::fs::read_to_string(path)?),
+            None => HashSet::new(),
+        };
+        Ok(Self {
+            env_key: env_key.filter(|k| !k.is_empty()),
+            file,
+            file_keys: RwLock::new(file_keys),
+        })
+    }
+
+    /// Whether any key is required at all. A configured key file always
+    /// enforces auth, even while it's empty.
+    pub fn required(&self) -> bool {
+        self.env_key.is_some() || self.file.is_some()
+    }
+
+    pub fn accepts(&self, token: &str) -> bool {
+        self.env_key.as_deref() == Some(token) || self.file_keys.read().unwrap().contains(token)
+    }
+
+    /// Re-read the key file whenever its modification time changes. If the
+    /// file can't be read, the last good set of keys stays in effect.
+    pub async fn watch(&self) {
+        let Some(path) = &self.file else {
+            return;
+        };
+        let mut last_modified = modified(path).await;
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let current = modified(path).await;
+            if current.is_none() || current == last_modified {
+                continue;
+            }
+            last_modified = current;
+
+            let keys = match tokio::fs::read_to_string(path).await {
+                Ok(contents) => parse(&contents),
+                Err(e) => {
+                    log::warn!("Failed to reload API keys from {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let mut file_keys = self.file_keys.write().unwrap();
+            let added = keys.difference(&file_keys).count();
+            let revoked = file_keys.difference(&keys).count();
+            *file_keys = keys;
+            log::info!(
+                "Reloaded API keys: {} added, {} revoked, {} active",
+                added,
+                revoked,
+                file_keys.len()
+            );
+        }
+    }
+}
+
+/// One key per line; blank lines and `#` comments are ignored.
+fn parse(contents: &str) -> HashSet<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+async fn modified(path: &PathBuf) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.ok()?.modified().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_skips_comments_and_blank_lines() {
+        let keys = parse("# ops team\nkey-one\n\n  key-two  \n#revoked\n");
+        assert_eq!(
+            keys,
+            HashSet::from(["key-one".to_string(), "key-two".to_string()])
+        );
+    }
+
+    #[test]
+    fn empty_env_key_requires_nothing() {
+        let keys = ApiKeys::load(Some(String::new()), None).unwrap();
+        assert!(!keys.required());
+    }
+
+    #[test]
+    fn env_key_is_accepted() {
+        let keys = ApiKeys::load(Some("secret".to_string()), None).unwrap();
+        assert!(keys.required());
+        assert!(keys.accepts("secret"));
+        assert!(!keys.accepts("other"));
+        assert!(!keys.accepts(""));
+    }
+}
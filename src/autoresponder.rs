@@ -0,0 +1,248 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::persist;
+use crate::pipeline::{Action, Direction, MessageMiddleware, PipelineMessage};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchType {
+    /// Case-insensitive match of a whole word (or phrase) in the message.
+    Keyword,
+    Regex,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RuleAction {
+    Reply { text: String },
+    Webhook { url: String },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Rule {
+    pub id: String,
+    pub match_type: MatchType,
+    pub pattern: String,
+    pub action: RuleAction,
+    pub enabled: bool,
+}
+
+struct CompiledRule {
+    rule: Rule,
+    regex: Regex,
+}
+
+impl CompiledRule {
+    fn compile(rule: Rule) -> Result<Self, regex::Error> {
+        let regex = match rule.match_type {
+            MatchType::Regex => Regex::new(&rule.pattern)?,
+            MatchType::Keyword => {
+                Regex::new(&format!(r"(?i)\b{}\b", regex::escape(&rule.pattern)))?
+            }
+        };
+        Ok(Self { rule, regex })
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SavedConfig {
+    rules: Vec<Rule>,
+    /// IDs of the chats the auto-responder is switched off for.
+    disabled_chats: BTreeSet<String>,
+}
+
+struct Config {
+    rules: Vec<CompiledRule>,
+    disabled_chats: BTreeSet<String>,
+}
+
+/// Matches incoming messages against keyword/regex rules and answers with a
+/// canned reply or calls a webhook. Rules are persisted to
+/// `<data_dir>/autoresponder.json`. Each chat gets at most one automatic
+/// response per cooldown period.
+#[derive(Clone)]
+pub struct AutoResponder {
+    path: PathBuf,
+    cooldown: Duration,
+    config: Arc<Mutex<Config>>,
+    last_response: Arc<Mutex<HashMap<String, Instant>>>,
+    http: reqwest::Client,
+}
+
+impl AutoResponder {
+    pub fn load(data_dir: &str, cooldown: Duration) -> anyhow::Result<Self> {
+        let path = PathBuf::from_str(data_dir)
+            .unwrap()
+            .join("autoresponder.json");
+        let saved: SavedConfig = persist::load_json(&path)?;
+
+        let rules = saved
+            .rules
+            .into_iter()
+            .filter_map(|rule| match CompiledRule::compile(rule) {
+                Ok(compiled) => Some(compiled),
+                Err(e) => {
+                    log::warn!("Skipping invalid auto-responder rule: {}", e);
+                    None
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            path,
+            cooldown,
+            config: Arc::new(Mutex::new(Config {
+                rules,
+                disabled_chats: saved.disabled_chats,
+            })),
+            last_response: Arc::new(Mutex::new(HashMap::new())),
+            http: reqwest::Client::new(),
+        })
+    }
+
+    pub async fn rules(&self) -> (Vec<Rule>, Vec<String>) {
+        let config = self.config.lock().await;
+        (
+            config.rules.iter().map(|r| r.rule.clone()).collect(),
+            config.disabled_chats.iter().cloned().collect(),
+        )
+    }
+
+    pub async fn add_rule(
+        &self,
+        match_type: MatchType,
+        pattern: String,
+        action: RuleAction,
+        enabled: bool,
+    ) -> anyhow::Result<Rule> {
+        let rule = Rule {
+            id: uuid::Uuid::new_v4().to_string().to_uppercase(),
+            match_type,
+            pattern,
+            action,
+            enabled,
+        };
+        let compiled = CompiledRule::compile(rule.clone())?;
+
+        let mut config = self.config.lock().await;
+        config.rules.push(compiled);
+        self.save(&config).await?;
+        Ok(rule)
+    }
+
+    pub async fn remove_rule(&self, id: &str) -> anyhow::Result<bool> {
+        let mut config = self.config.lock().await;
+        let before = config.rules.len();
+        config.rules.retain(|r| r.rule.id != id);
+        if config.rules.len() == before {
+            return Ok(false);
+        }
+        self.save(&config).await?;
+        Ok(true)
+    }
+
+    pub async fn set_chat_enabled(&self, chat_id: String, enabled: bool) -> anyhow::Result<()> {
+        let mut config = self.config.lock().await;
+        if enabled {
+            config.disabled_chats.remove(&chat_id);
+        } else {
+            config.disabled_chats.insert(chat_id);
+        }
+        self.save(&config).await
+    }
+
+    async fn save(&self, config: &Config) -> anyhow::Result<()> {
+        let saved = SavedConfig {
+            rules: config.rules.iter().map(|r| r.rule.clone()).collect(),
+            disabled_chats: config.disabled_chats.clone(),
+        };
+        persist::write_atomic(&self.path, &serde_json::to_vec_pretty(&saved)?).await
+    }
+
+    /// Whether `chat` may receive another automatic response now; records
+    /// the response if so.
+    async fn take_slot(&self, chat: &str) -> bool {
+        let mut last_response = self.last_response.lock().await;
+        if let Some(last) = last_response.get(chat) {
+            if last.elapsed() < self.cooldown {
+                return false;
+            }
+        }
+        last_response.insert(chat.to_string(), Instant::now());
+        true
+    }
+}
+
+#[async_trait]
+impl MessageMiddleware for AutoResponder {
+    fn name(&self) -> &str {
+        "autoresponder"
+    }
+
+    async fn process(&self, message: &mut PipelineMessage) -> Action {
+        if message.direction != Direction::Inbound {
+            return Action::Continue;
+        }
+        // Messages sent from the account's other devices are echoed back to
+        // us; answering them would reply to ourselves.
+        if message.from_me {
+            return Action::Continue;
+        }
+        let (Some(chat), Some(text)) = (message.chat_id.clone(), message.text.clone()) else {
+            return Action::Continue;
+        };
+
+        let rule = {
+            let config = self.config.lock().await;
+            if config.disabled_chats.contains(&chat) {
+                return Action::Continue;
+            }
+            match config
+                .rules
+                .iter()
+                .find(|r| r.rule.enabled && r.regex.is_match(&text))
+            {
+                Some(compiled) => compiled.rule.clone(),
+                None => return Action::Continue,
+            }
+        };
+
+        if !self.take_slot(&chat).await {
+            log::debug!("Auto-responder rate limited for chat {}", chat);
+            return Action::Continue;
+        }
+
+        message
+            .annotations
+            .insert("autoresponder_rule".to_string(), rule.id.clone());
+
+        match rule.action {
+            RuleAction::Reply { text } => Action::Reply(text),
+            RuleAction::Webhook { url } => {
+                let http = self.http.clone();
+                let payload = message.clone();
+                tokio::spawn(async move {
+                    let result = http
+                        .post(&url)
+                        .json(&payload)
+                        .send()
+                        .await
+                        .and_then(|r| r.error_for_status());
+                    if let Err(e) = result {
+                        log::warn!("Auto-responder webhook {} failed: {}", url, e);
+                    }
+                });
+                Action::Continue
+            }
+        }
+    }
+}
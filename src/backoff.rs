@@ -0,0 +1,194 @@
+use std::fmt;
+use std::io::ErrorKind;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rustpush::PushError;
+
+/// Upstream failures that mean Apple wants us to slow down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpstreamError {
+    RateLimited,
+    TemporaryIds,
+}
+
+impl UpstreamError {
+    pub fn classify(err: &PushError) -> Option<Self> {
+        match err {
+            PushError::StatusError(status) if status.as_u16() == 429 => Some(Self::RateLimited),
+            PushError::StatusError(status) if status.is_server_error() => Some(Self::TemporaryIds),
+            PushError::LookupFailed(_) => Some(Self::TemporaryIds),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::RateLimited => "rate limited by Apple",
+            Self::TemporaryIds => "temporary IDS failure",
+        }
+    }
+}
+
+/// Failures worth retrying straight away: the connection to Apple dropped or
+/// timed out, rather than Apple rejecting the message.
+pub fn is_transient(err: &PushError) -> bool {
+    match err {
+        PushError::IoError(e) => matches!(
+            e.kind(),
+            ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::BrokenPipe
+                | ErrorKind::TimedOut
+                | ErrorKind::UnexpectedEof
+        ),
+        PushError::ReqwestError(e) => e.is_connect() || e.is_timeout(),
+        PushError::SendTimedOut => true,
+        _ => false,
+    }
+}
+
+/// Sends are refused until the global cooldown expires.
+#[derive(Debug)]
+pub struct CoolingDown {
+    pub retry_after: Duration,
+    pub reason: UpstreamError,
+}
+
+impl fmt::Display for CoolingDown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Backing off after upstream error ({}); retry in {}s",
+            self.reason.as_str(),
+            self.retry_after.as_secs().max(1)
+        )
+    }
+}
+
+impl std::error::Error for CoolingDown {}
+
+struct BackoffState {
+    until: Option<(Instant, UpstreamError)>,
+    next: Duration,
+}
+
+/// Global exponential backoff shared by every send. Each rate-limit or
+/// temporary IDS error doubles the cooldown (up to `max`); a successful send
+/// resets it.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    state: Mutex<BackoffState>,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            state: Mutex::new(BackoffState {
+                until: None,
+                next: base,
+            }),
+        }
+    }
+
+    pub fn check(&self) -> Result<(), CoolingDown> {
+        let state = self.state.lock().unwrap();
+        match state.until {
+            Some((until, reason)) if until > Instant::now() => Err(CoolingDown {
+                retry_after: until - Instant::now(),
+                reason,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Time left on the current cooldown, if any.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.check().err().map(|c| c.retry_after)
+    }
+
+    pub fn observe(&self, err: &PushError) {
+        let Some(reason) = UpstreamError::classify(err) else {
+            return;
+        };
+        let cooldown = self.cool_down(reason);
+        log::warn!(
+            "Backing off for {:?} after upstream error: {}",
+            cooldown,
+            err
+        );
+    }
+
+    /// Start the next cooldown and return how long it is.
+    fn cool_down(&self, reason: UpstreamError) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let cooldown = state.next;
+        state.until = Some((Instant::now() + cooldown, reason));
+        state.next = (cooldown * 2).min(self.max);
+        cooldown
+    }
+
+    pub fn succeeded(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.until = None;
+        state.next = self.base;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff() -> Backoff {
+        Backoff::new(Duration::from_secs(1), Duration::from_secs(5))
+    }
+
+    #[test]
+    fn starts_without_cooldown() {
+        let backoff = backoff();
+        assert!(backoff.check().is_ok());
+        assert_eq!(backoff.remaining(), None);
+    }
+
+    #[test]
+    fn cooldown_doubles_up_to_max() {
+        let backoff = backoff();
+        let cooldowns: Vec<u64> = (0..5)
+            .map(|_| backoff.cool_down(UpstreamError::RateLimited).as_secs())
+            .collect();
+        assert_eq!(cooldowns, [1, 2, 4, 5, 5]);
+    }
+
+    #[test]
+    fn check_reports_cooldown() {
+        let backoff = backoff();
+        backoff.cool_down(UpstreamError::TemporaryIds);
+        let err = backoff.check().unwrap_err();
+        assert_eq!(err.reason, UpstreamError::TemporaryIds);
+        assert!(err.retry_after <= Duration::from_secs(1));
+        assert!(backoff.remaining().is_some());
+    }
+
+    #[test]
+    fn success_resets_cooldown() {
+        let backoff = backoff();
+        backoff.cool_down(UpstreamError::RateLimited);
+        backoff.cool_down(UpstreamError::RateLimited);
+        backoff.succeeded();
+        assert!(backoff.check().is_ok());
+        assert_eq!(
+            backoff.cool_down(UpstreamError::RateLimited),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn other_errors_are_ignored() {
+        let backoff = backoff();
+        backoff.observe(&PushError::SendTimedOut);
+        assert!(backoff.check().is_ok());
+    }
+}
@@ -0,0 +1,178 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use serde_json::json;
+use tokio::sync::Mutex;
+
+struct Options {
+    url: String,
+    to: String,
+    count: usize,
+    rate: f64,
+    /// Time between two requests, `1 / rate`.
+    interval: Duration,
+    dry_run: bool,
+}
+
+const USAGE: &str = "usage: imessage-api bench --to <handle> [--count 10] [--rate 1] [--dry-run] [--url http://127.0.0.1:8787]";
+
+fn parse(args: &[String]) -> anyhow::Result<Options> {
+    let port = std::env::var("IMESSAGE_API_PORT").unwrap_or_else(|_| "8787".to_string());
+    let mut options = Options {
+        url: format!("http://127.0.0.1:{}", port),
+        to: String::new(),
+        count: 10,
+        rate: 1.0,
+        interval: Duration::from_secs(1),
+        dry_run: false,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().context(USAGE);
+        match arg.as_str() {
+            "--url" => options.url = value()?.trim_end_matches('/').to_string(),
+            "--to" => options.to = value()?.clone(),
+            "--count" => options.count = value()?.parse().context("--count must be a number")?,
+            "--rate" => options.rate = value()?.parse().context("--rate must be a number")?,
+            "--dry-run" => options.dry_run = true,
+            _ => anyhow::bail!("Unknown option {:?}\n{}", arg, USAGE),
+        }
+    }
+    if options.to.is_empty() || options.count == 0 {
+        anyhow::bail!(USAGE);
+    }
+    // NaN, infinite, negative or extreme rates would panic building the
+    // ticker: the interval must fit a Duration and not round to zero
+    options.interval = Some(options.rate)
+        .filter(|rate| rate.is_finite() && *rate > 0.0)
+        .and_then(|rate| Duration::try_from_secs_f64(1.0 / rate).ok())
+        .filter(|interval| !interval.is_zero())
+        .with_context(|| {
+            format!(
+                "--rate must be a positive number of messages per second, got {}",
+                options.rate
+            )
+        })?;
+    Ok(options)
+}
+
+/// `imessage-api bench`: drive `POST /api/send` on a running server at a
+/// fixed rate and report throughput and latency percentiles. Requests are
+/// started on schedule whether or not earlier ones have finished, so a
+/// slow server shows up as latency rather than a lower send rate.
+/// Point `--to` at one of your own handles; without `--dry-run` every
+/// request is a real message.
+pub async fn run(args: &[String]) -> anyhow::Result<()> {
+    let options = parse(args)?;
+    let api_key = std::env::var("IMESSAGE_API_KEY").unwrap_or_default();
+    let client = reqwest::Client::new();
+    let url = format!("{}/api/send", options.url);
+
+    println!(
+        "Sending {} messages to {} at {}/s{}",
+        options.count,
+        options.to,
+        options.rate,
+        if options.dry_run { " (dry run)" } else { "" }
+    );
+
+    let latencies = Arc::new(Mutex::new(vec![]));
+    let failures = Arc::new(Mutex::new(vec![]));
+    let started = Instant::now();
+    let mut ticker = tokio::time::interval(options.interval);
+    let mut tasks = vec![];
+    for i in 0..options.count {
+        ticker.tick().await;
+        let request = client.post(&url).bearer_auth(&api_key).json(&json!({
+            "to": options.to,
+            "message": format!("bench {}/{}", i + 1, options.count),
+            "dry_run": options.dry_run,
+        }));
+        let latencies = latencies.clone();
+        let failures = failures.clone();
+        tasks.push(tokio::spawn(async move {
+            let sent = Instant::now();
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {
+                    latencies.lock().await.push(sent.elapsed());
+                }
+                Ok(response) => failures.lock().await.push(response.status().to_string()),
+                Err(e) => failures.lock().await.push(e.to_string()),
+            }
+        }));
+    }
+    for task in tasks {
+        task.await?;
+    }
+    let elapsed = started.elapsed();
+
+    let mut latencies = latencies.lock().await.clone();
+    latencies.sort();
+    let failures = failures.lock().await;
+    println!(
+        "\n{} ok, {} failed in {:.1}s ({:.2} sends/s)",
+        latencies.len(),
+        failures.len(),
+        elapsed.as_secs_f64(),
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    if !latencies.is_empty() {
+        println!(
+            "latency p50 {:?}  p90 {:?}  p99 {:?}  max {:?}",
+            percentile(&latencies, 50),
+            percentile(&latencies, 90),
+            percentile(&latencies, 99),
+            latencies[latencies.len() - 1]
+        );
+    }
+    for failure in failures.iter() {
+        println!("failed: {}", failure);
+    }
+    Ok(())
+}
+
+/// Nearest-rank percentile of sorted latencies.
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    let rank = (sorted.len() * p).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn percentile_uses_nearest_rank() {
+        let latencies: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 50), Duration::from_millis(50));
+        assert_eq!(percentile(&latencies, 95), Duration::from_millis(95));
+        assert_eq!(percentile(&latencies, 99), Duration::from_millis(99));
+        assert_eq!(percentile(&latencies, 0), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn percentile_of_few_samples() {
+        let latencies = [Duration::from_millis(10), Duration::from_millis(20)];
+        assert_eq!(percentile(&latencies, 50), Duration::from_millis(10));
+        assert_eq!(percentile(&latencies, 99), Duration::from_millis(20));
+        assert_eq!(percentile(&latencies[..1], 99), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn parse_rejects_unusable_rates() {
+        for rate in ["0", "-1", "NaN", "inf", "1e300", "1e-300"] {
+            assert!(
+                parse(&args(&["--to", "me@example.com", "--rate", rate])).is_err(),
+                "{:?}",
+                rate
+            );
+        }
+        let options = parse(&args(&["--to", "me@example.com", "--rate", "4"])).unwrap();
+        assert_eq!(options.interval, Duration::from_millis(250));
+    }
+}
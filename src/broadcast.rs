@@ -0,0 +1,519 @@
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use log::info;
+use rustpush::PushError;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, Mutex, Semaphore};
+use tokio::task::JoinSet;
+
+use crate::backoff::{self, CoolingDown, UpstreamError};
+use crate::handlers::{format_phone, AppState};
+use crate::persist::{self, JsonLog};
+use crate::send::{self, RecipientNotAllowed, RecipientNotIMessage, SendOptions};
+use crate::session::{self, NoSession};
+use crate::types::BroadcastStatusResponse;
+
+/// Delay before retrying recipients that hit a transient error, doubled
+/// after every round up to `MAX_RETRY_DELAY`.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecipientStatus {
+    Pending,
+    Sent,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BroadcastRecipient {
+    pub to: String,
+    /// Pre-assigned so a resumed job re-sends with the same GUID.
+    pub message_id: String,
+    pub status: RecipientStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BroadcastJob {
+    pub id: String,
+    pub message: String,
+    pub concurrency: usize,
+    pub interval_ms: u64,
+    pub recipients: Vec<BroadcastRecipient>,
+}
+
+/// Progress update emitted after each recipient of a broadcast completes.
+#[derive(Serialize, Clone)]
+pub struct BroadcastEvent {
+    pub to: String,
+    pub message_id: String,
+    pub status: RecipientStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub total: usize,
+    pub sent: usize,
+    pub failed: usize,
+    pub pending: usize,
+    pub done: bool,
+}
+
+/// The outcome of one send, as appended to a job's progress log.
+#[derive(Serialize, Deserialize)]
+struct Progress {
+    index: usize,
+    status: RecipientStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// A job together with the channel its progress events are published on.
+pub struct BroadcastHandle {
+    pub job: Mutex<BroadcastJob>,
+    pub events: broadcast::Sender<BroadcastEvent>,
+    /// Sends recorded since the job file was last written. Only appended to
+    /// while `job` is locked.
+    progress: Mutex<JsonLog>,
+    /// Set while `run` is sending this job.
+    running: AtomicBool,
+}
+
+impl BroadcastHandle {
+    fn new(job: BroadcastJob, progress: JsonLog) -> Arc<Self> {
+        let (events, _) = broadcast::channel(64);
+        Arc::new(Self {
+            job: Mutex::new(job),
+            events,
+            progress: Mutex::new(progress),
+            running: AtomicBool::new(false),
+        })
+    }
+}
+
+impl BroadcastJob {
+    pub fn new(
+        message: String,
+        recipients: Vec<String>,
+        concurrency: usize,
+        interval_ms: u64,
+    ) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string().to_uppercase(),
+            message,
+            concurrency: concurrency.max(1),
+            interval_ms,
+            recipients: recipients
+                .into_iter()
+                .map(|to| BroadcastRecipient {
+                    to,
+                    message_id: uuid::Uuid::new_v4().to_string().to_uppercase(),
+                    status: RecipientStatus::Pending,
+                    error: None,
+                })
+                .collect(),
+        }
+    }
+
+    fn count(&self, status: RecipientStatus) -> usize {
+        self.recipients
+            .iter()
+            .filter(|r| r.status == status)
+            .count()
+    }
+
+    fn apply(&mut self, progress: Progress) -> anyhow::Result<()> {
+        let Some(recipient) = self.recipients.get_mut(progress.index) else {
+            anyhow::bail!("no recipient {}", progress.index);
+        };
+        recipient.status = progress.status;
+        recipient.error = progress.error;
+        Ok(())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.count(RecipientStatus::Pending) == 0
+    }
+
+    fn event(&self, index: usize) -> BroadcastEvent {
+        let recipient = &self.recipients[index];
+        BroadcastEvent {
+            to: recipient.to.clone(),
+            message_id: recipient.message_id.clone(),
+            status: recipient.status,
+            error: recipient.error.clone(),
+            total: self.recipients.len(),
+            sent: self.count(RecipientStatus::Sent),
+            failed: self.count(RecipientStatus::Failed),
+            pending: self.count(RecipientStatus::Pending),
+            done: self.is_finished(),
+        }
+    }
+
+    pub fn status(&self) -> BroadcastStatusResponse {
+        BroadcastStatusResponse {
+            id: self.id.clone(),
+            total: self.recipients.len(),
+            sent: self.count(RecipientStatus::Sent),
+            failed: self.count(RecipientStatus::Failed),
+            pending: self.count(RecipientStatus::Pending),
+            done: self.is_finished(),
+            recipients: self.recipients.clone(),
+        }
+    }
+}
+
+/// Broadcast jobs, persisted as one JSON file per job under
+/// `<data_dir>/broadcasts` so unfinished jobs can be resumed after a crash.
+/// The outcome of each send is appended to the job's `<id>.progress.jsonl`,
+/// which is folded back into the job file once it has grown as long as the
+/// recipient list.
+pub struct Broadcasts {
+    dir: PathBuf,
+    jobs: Mutex<HashMap<String, Arc<BroadcastHandle>>>,
+    pub default_concurrency: usize,
+    pub max_concurrency: usize,
+    pub default_interval_ms: u64,
+}
+
+impl Broadcasts {
+    pub fn load(
+        data_dir: &str,
+        default_concurrency: usize,
+        max_concurrency: usize,
+        default_interval_ms: u64,
+    ) -> anyhow::Result<Self> {
+        let dir = PathBuf::from_str(data_dir).unwrap().join("broadcasts");
+
+        let mut jobs = HashMap::new();
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries.collect::<std::io::Result<Vec<_>>>(),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e),
+        }
+        .with_context(|| format!("Failed to read {}", dir.display()))?;
+        for entry in entries {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let bytes = std::fs::read(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let mut job: BroadcastJob = serde_json::from_slice(&bytes).with_context(|| {
+                format!(
+                    "{} is not a valid broadcast job; repair or remove it to start fresh",
+                    path.display()
+                )
+            })?;
+            let log_path = progress_path(&dir, &job.id);
+            let (progress, entries) = JsonLog::load::<Progress>(log_path.clone())?;
+            for entry in entries {
+                job.apply(entry)
+                    .with_context(|| format!("{} doesn't match its job", log_path.display()))?;
+            }
+            jobs.insert(job.id.clone(), BroadcastHandle::new(job, progress));
+        }
+
+        Ok(Self {
+            dir,
+            jobs: Mutex::new(jobs),
+            default_concurrency,
+            max_concurrency,
+            default_interval_ms,
+        })
+    }
+
+    pub async fn insert(&self, job: BroadcastJob) -> anyhow::Result<Arc<BroadcastHandle>> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+        self.save(&job).await?;
+        let job_id = job.id.clone();
+        let (progress, _) = JsonLog::load::<Progress>(progress_path(&self.dir, &job_id))?;
+        let handle = BroadcastHandle::new(job, progress);
+        self.jobs.lock().await.insert(job_id, handle.clone());
+        Ok(handle)
+    }
+
+    pub async fn get(&self, id: &str) -> Option<Arc<BroadcastHandle>> {
+        self.jobs.lock().await.get(id).cloned()
+    }
+
+    pub async fn unfinished(&self) -> Vec<Arc<BroadcastHandle>> {
+        let mut unfinished = vec![];
+        for handle in self.jobs.lock().await.values() {
+            if !handle.job.lock().await.is_finished() {
+                unfinished.push(handle.clone());
+            }
+        }
+        unfinished
+    }
+
+    /// Number of unfinished jobs and of recipients still waiting across them.
+    pub async fn backlog(&self) -> (usize, usize) {
+        let mut jobs = 0;
+        let mut recipients = 0;
+        for handle in self.jobs.lock().await.values() {
+            let pending = handle.job.lock().await.count(RecipientStatus::Pending);
+            if pending > 0 {
+                jobs += 1;
+                recipients += pending;
+            }
+        }
+        (jobs, recipients)
+    }
+
+    async fn save(&self, job: &BroadcastJob) -> anyhow::Result<()> {
+        let path = self.dir.join(format!("{}.json", job.id));
+        persist::write_atomic(&path, &serde_json::to_vec(job)?).await
+    }
+
+    /// Append the outcome of recipient `index` to the job's progress log,
+    /// folding the log into the job file once it is as long as the job.
+    async fn record(
+        &self,
+        job: &BroadcastJob,
+        progress: &mut JsonLog,
+        index: usize,
+    ) -> anyhow::Result<()> {
+        let recipient = &job.recipients[index];
+        progress
+            .append(&Progress {
+                index,
+                status: recipient.status,
+                error: recipient.error.clone(),
+            })
+            .await?;
+        if progress.lines() >= job.recipients.len().max(1) {
+            // Replaying the old log over the new job file restores the same
+            // state, so a crash between the two writes is harmless
+            self.save(job).await?;
+            progress.rewrite(std::iter::empty::<&Progress>()).await?;
+        }
+        Ok(())
+    }
+}
+
+fn progress_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.progress.jsonl", id))
+}
+
+/// Send every pending recipient of `job`, at most `concurrency` at a time and
+/// starting a new send no more often than every `interval_ms`. Keys for all
+/// pending recipients are looked up in one batch first. Progress is
+/// persisted and published on the job's event channel after each recipient.
+/// Recipients that hit a transient error stay pending and are retried in
+/// later rounds, with a growing delay, until none are left.
+pub async fn run(state: Arc<AppState>, handle: Arc<BroadcastHandle>) {
+    // A job created while no session was loaded is also resumed when the
+    // first session comes up; only one run may send it
+    if handle.running.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let mut retry_delay = RETRY_DELAY;
+    loop {
+        let left = send_round(&state, &handle).await;
+        if left == 0 {
+            break;
+        }
+        let id = handle.job.lock().await.id.clone();
+        log::warn!(
+            "Broadcast {}: {} recipients hit transient errors; retrying in {}s",
+            id,
+            left,
+            retry_delay.as_secs()
+        );
+        tokio::time::sleep(retry_delay).await;
+        retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+    }
+    handle.running.store(false, Ordering::SeqCst);
+
+    let job = handle.job.lock().await;
+    info!(
+        "Broadcast {} finished: {} sent, {} failed",
+        job.id,
+        job.count(RecipientStatus::Sent),
+        job.count(RecipientStatus::Failed)
+    );
+}
+
+/// One pass over the pending recipients. Returns how many are still
+/// pending afterwards because of a transient error.
+async fn send_round(state: &Arc<AppState>, handle: &Arc<BroadcastHandle>) -> usize {
+    let (id, text, concurrency, interval_ms, pending) = {
+        let job = handle.job.lock().await;
+        let pending: Vec<(usize, String, String)> = job
+            .recipients
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.status == RecipientStatus::Pending)
+            .map(|(i, r)| (i, r.to.clone(), r.message_id.clone()))
+            .collect();
+        (
+            job.id.clone(),
+            job.message.clone(),
+            job.concurrency,
+            job.interval_ms,
+            pending,
+        )
+    };
+    if pending.is_empty() {
+        return 0;
+    }
+
+    info!("Broadcast {}: {} recipients pending", id, pending.len());
+
+    // Refuse recipients the sandbox allowlist rules out the same way a single
+    // send would, before they are looked up
+    let mut allowed = Vec::with_capacity(pending.len());
+    for (index, to, message_id) in pending {
+        // Jobs are checked when created, but one saved before that may not be
+        let target = match format_phone(&to) {
+            Ok(target) => target,
+            Err(e) => {
+                record(state, handle, index, &to, Err(e.into())).await;
+                continue;
+            }
+        };
+        if let Some(allowlist) = &state.allowed_recipients {
+            if !allowlist.contains(&target) {
+                let error = RecipientNotAllowed {
+                    handles: vec![target],
+                };
+                record(state, handle, index, &to, Err(error.into())).await;
+                continue;
+            }
+        }
+        allowed.push((index, to, message_id, target));
+    }
+
+    let targets: Vec<String> = allowed
+        .iter()
+        .map(|(_, _, _, target)| target.clone())
+        .collect();
+    let reachable = resolve(state, &targets).await;
+
+    // Jobs saved before the limit existed may ask for more
+    let concurrency = concurrency.clamp(1, state.broadcasts.max_concurrency);
+    let semaphore = Arc::new(Semaphore::new(concurrency));
+    let mut pacing = tokio::time::interval(Duration::from_millis(interval_ms.max(1)));
+    let mut tasks = JoinSet::new();
+
+    for (index, to, message_id, target) in allowed {
+        let keys_resolved = match &reachable {
+            Some(reachable) if !reachable.contains(&target) => {
+                let error = RecipientNotIMessage {
+                    handles: vec![target],
+                };
+                record(state, handle, index, &to, Err(error.into())).await;
+                continue;
+            }
+            Some(_) => true,
+            None => false,
+        };
+
+        pacing.tick().await;
+        // Wait out a global backoff rather than failing every remaining recipient
+        while let Some(wait) = state.backoff.remaining() {
+            tokio::time::sleep(wait).await;
+        }
+        let permit = semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("broadcast semaphore closed");
+
+        let state = state.clone();
+        let handle = handle.clone();
+        let text = text.clone();
+        tasks.spawn(async move {
+            let options = SendOptions {
+                keys_resolved,
+                ..SendOptions::with_id(message_id)
+            };
+            let result = send::send_text(&state, &to, &text, options)
+                .await
+                .map(|sent| sent.message_id);
+            drop(permit);
+            record(&state, &handle, index, &to, result).await;
+        });
+    }
+
+    while tasks.join_next().await.is_some() {}
+
+    handle.job.lock().await.count(RecipientStatus::Pending)
+}
+
+/// Failures that say nothing about the recipient: no session, a global
+/// backoff, rate limiting or a dropped connection.
+fn is_transient(err: &anyhow::Error) -> bool {
+    err.is::<NoSession>()
+        || err.is::<CoolingDown>()
+        || err
+            .downcast_ref::<PushError>()
+            .is_some_and(|e| backoff::is_transient(e) || UpstreamError::classify(e).is_some())
+}
+
+/// Look up keys for all `recipients`, as formatted handles, in one IDS query
+/// instead of one per send. Returns the handles that are on iMessage, or
+/// `None` if the lookup couldn't be done and each send has to look up its own.
+async fn resolve(state: &AppState, recipients: &[String]) -> Option<HashSet<String>> {
+    let session = state.session().ok()?;
+    if recipients.is_empty() {
+        return None;
+    }
+    let sender = session.client.identity.get_handles().await.first()?.clone();
+    match session::lookup_keys(&session.client, &sender, recipients, &state.metrics).await {
+        Ok(reachable) => Some(reachable.into_iter().collect()),
+        Err(e) => {
+            log::warn!("Batched key lookup failed, looking up per send: {}", e);
+            state.backoff.observe(&e);
+            None
+        }
+    }
+}
+
+/// Store the outcome of the send to recipient `index`, log it and publish
+/// the progress event. A transient failure leaves it pending.
+async fn record(
+    state: &AppState,
+    handle: &BroadcastHandle,
+    index: usize,
+    to: &str,
+    result: anyhow::Result<String>,
+) {
+    let mut job = handle.job.lock().await;
+    match result {
+        Ok(_) => {
+            let recipient = &mut job.recipients[index];
+            recipient.status = RecipientStatus::Sent;
+            recipient.error = None;
+        }
+        Err(e) if is_transient(&e) => {
+            // Left pending for the next round; the error shows why it's late
+            log::info!("Broadcast {} to {} will be retried: {}", job.id, to, e);
+            job.recipients[index].error = Some(e.to_string());
+        }
+        Err(e) => {
+            log::warn!("Broadcast {} to {} failed: {}", job.id, to, e);
+            let recipient = &mut job.recipients[index];
+            recipient.status = RecipientStatus::Failed;
+            recipient.error = Some(e.to_string());
+        }
+    }
+    let mut progress = handle.progress.lock().await;
+    if let Err(e) = state.broadcasts.record(&job, &mut progress, index).await {
+        log::error!("Failed to persist broadcast {}: {}", job.id, e);
+    }
+    // No subscribers is the common case; nothing to do then
+    let _ = handle.events.send(job.event(index));
+}
@@ -0,0 +1,197 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustpush::{Message, MessageType, NormalMessage, ReactMessage, ReactMessageType, Reaction};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::chats;
+use crate::handlers::AppState;
+use crate::pipeline::{Direction, PipelineMessage};
+use crate::send::{self, SendOptions, Target};
+
+#[derive(Serialize, Clone)]
+struct HistoryEntry {
+    direction: Direction,
+    sender: Option<String>,
+    text: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BotRequest<'a> {
+    message: &'a PipelineMessage,
+    /// Earlier messages in this conversation, oldest first.
+    history: Vec<HistoryEntry>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum Tapback {
+    Love,
+    Like,
+    Dislike,
+    Laugh,
+    Emphasize,
+    Question,
+}
+
+impl Tapback {
+    fn reaction(self) -> Reaction {
+        match self {
+            Self::Love => Reaction::Heart,
+            Self::Like => Reaction::Like,
+            Self::Dislike => Reaction::Dislike,
+            Self::Laugh => Reaction::Laugh,
+            Self::Emphasize => Reaction::Emphsize,
+            Self::Question => Reaction::Question,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct BotAttachment {
+    url: String,
+}
+
+#[derive(Deserialize, Default)]
+struct BotReply {
+    text: Option<String>,
+    tapback: Option<Tapback>,
+    #[serde(default)]
+    attachments: Vec<BotAttachment>,
+}
+
+/// Forwards every accepted inbound message to an HTTP endpoint along with
+/// recent conversation history, and sends back whatever the endpoint replies.
+pub struct Chatbot {
+    url: String,
+    context_size: usize,
+    http: reqwest::Client,
+    history: Mutex<HashMap<String, VecDeque<HistoryEntry>>>,
+}
+
+impl Chatbot {
+    pub fn new(url: String, context_size: usize, timeout: Duration) -> Self {
+        Self {
+            url,
+            context_size,
+            http: reqwest::Client::builder()
+                .timeout(timeout)
+                .build()
+                .expect("failed to build HTTP client"),
+            history: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn dispatch(self: &Arc<Self>, state: Arc<AppState>, message: PipelineMessage) {
+        let chatbot = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = chatbot.route(&state, &message).await {
+                log::warn!("Chatbot failed on message {}: {}", message.id, e);
+            }
+        });
+    }
+
+    /// History is kept per chat, so it follows the chat when Apple assigns
+    /// a group GUID partway through.
+    fn conversation_key(message: &PipelineMessage) -> String {
+        match &message.chat_id {
+            Some(chat_id) => chat_id.clone(),
+            None => chats::chat_id(&message.participants, message.group_id.as_deref()),
+        }
+    }
+
+    async fn remember(&self, key: &str, entry: HistoryEntry) -> Vec<HistoryEntry> {
+        let mut history = self.history.lock().await;
+        let entries = history.entry(key.to_string()).or_default();
+        let previous = entries.iter().cloned().collect();
+        entries.push_back(entry);
+        while entries.len() > self.context_size {
+            entries.pop_front();
+        }
+        previous
+    }
+
+    async fn route(&self, state: &AppState, message: &PipelineMessage) -> anyhow::Result<()> {
+        if message.from_me {
+            return Ok(());
+        }
+        let own_handles = state
+            .session()?
+            .client
+            .identity
+            .get_handles()
+            .await
+            .to_vec();
+
+        let key = Self::conversation_key(message);
+        let history = self
+            .remember(
+                &key,
+                HistoryEntry {
+                    direction: Direction::Inbound,
+                    sender: message.sender.clone(),
+                    text: message.text.clone(),
+                },
+            )
+            .await;
+
+        let response = self
+            .http
+            .post(&self.url)
+            .json(&BotRequest { message, history })
+            .send()
+            .await?
+            .error_for_status()?;
+        // An empty body means "no reply"
+        let body = response.bytes().await?;
+        let reply: BotReply = if body.is_empty() {
+            BotReply::default()
+        } else {
+            serde_json::from_slice(&body)?
+        };
+
+        if let Some(tapback) = reply.tapback {
+            let react = ReactMessage {
+                to_uuid: message.id.clone(),
+                to_part: Some(0),
+                reaction: ReactMessageType::React {
+                    reaction: tapback.reaction(),
+                    enable: true,
+                },
+                to_text: message.text.clone().unwrap_or_default(),
+                embedded_profile: None,
+            };
+            let target = Target::reply_to(message, &own_handles);
+            send::send(state, target, Message::React(react), SendOptions::default()).await?;
+        }
+
+        // Attachment upload isn't supported yet; send attachments as links
+        let mut lines: Vec<String> = reply.text.into_iter().collect();
+        lines.extend(reply.attachments.into_iter().map(|a| a.url));
+        if !lines.is_empty() {
+            let text = lines.join("\n");
+            let normal = NormalMessage::new(text.clone(), MessageType::IMessage);
+            let target = Target::reply_to(message, &own_handles);
+            send::send(
+                state,
+                target,
+                Message::Message(normal),
+                SendOptions::default(),
+            )
+            .await?;
+            self.remember(
+                &key,
+                HistoryEntry {
+                    direction: Direction::Outbound,
+                    sender: own_handles.first().cloned(),
+                    text: Some(text),
+                },
+            )
+            .await;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::handlers::AppState;
+use crate::notes::Note;
+use crate::persist;
+use crate::pipeline::PipelineMessage;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Chat {
+    pub id: String,
+    pub participants: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_name: Option<String>,
+    #[serde(default)]
+    pub labels: BTreeSet<String>,
+    #[serde(default)]
+    pub assignee: Option<String>,
+    #[serde(default)]
+    pub notes: Vec<Note>,
+    /// Unix timestamp (seconds) of the last message sent or received.
+    pub last_activity: u64,
+    /// When each participant last sent a message in this chat.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub last_active: HashMap<String, u64>,
+    /// GUID of the latest message sent or received, passed as `after_guid`
+    /// on the next send so it threads after it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_message_id: Option<String>,
+}
+
+#[derive(Default)]
+pub struct ChatFilter {
+    pub label: Option<String>,
+    pub assignee: Option<String>,
+    pub unassigned: bool,
+}
+
+impl ChatFilter {
+    fn matches(&self, chat: &Chat) -> bool {
+        if let Some(label) = &self.label {
+            if !chat.labels.contains(label) {
+                return false;
+            }
+        }
+        if let Some(assignee) = &self.assignee {
+            if chat.assignee.as_ref() != Some(assignee) {
+                return false;
+            }
+        }
+        !(self.unassigned && chat.assignee.is_some())
+    }
+}
+
+/// Whether a conversation with these (sorted, deduplicated) participants,
+/// ourselves included, is a group chat.
+fn is_group(sorted: &[String]) -> bool {
+    sorted.len() > 2
+}
+
+/// What a chat's ID is derived from: the group GUID for group chats that
+/// have one, otherwise the sorted participant handles. One-on-one chats
+/// ignore the GUID, since Apple attaches one to those too and it isn't
+/// known until the first reply.
+fn chat_key(sorted: &[String], group_id: Option<&str>) -> String {
+    match group_id.filter(|_| is_group(sorted)) {
+        Some(group_id) => format!("group:{}", group_id),
+        None => sorted.join(","),
+    }
+}
+
+fn sorted(participants: &[String]) -> Vec<String> {
+    let mut sorted = participants.to_vec();
+    sorted.sort();
+    sorted.dedup();
+    sorted
+}
+
+/// How often activity on known chats is saved.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Namespace of the UUIDv5 chat IDs.
+const CHAT_ID_NAMESPACE: Uuid = Uuid::from_u128(0xE64D0CE6_7ADD_4C34_A991_4C3706B4FA6A);
+
+/// The stable ID a new chat gets, so the same people always map to the
+/// same chat: derived from the group GUID of a group chat when Apple
+/// assigned one, otherwise from the sorted participant handles.
+pub fn chat_id(participants: &[String], group_id: Option<&str>) -> String {
+    let key = chat_key(&sorted(participants), group_id);
+    Uuid::new_v5(&CHAT_ID_NAMESPACE, key.as_bytes())
+        .to_string()
+        .to_uppercase()
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Default)]
+struct Inner {
+    chats: HashMap<String, Chat>,
+    /// Chat IDs by `chat_key`: every chat under its participants, and group
+    /// chats also under their GUID.
+    index: HashMap<String, String>,
+    /// Activity has been recorded since the last save.
+    dirty: bool,
+}
+
+impl Inner {
+    fn insert(&mut self, chat: Chat) {
+        let sorted = sorted(&chat.participants);
+        self.index.insert(chat_key(&sorted, None), chat.id.clone());
+        if chat.group_id.is_some() && is_group(&sorted) {
+            self.index
+                .insert(chat_key(&sorted, chat.group_id.as_deref()), chat.id.clone());
+        }
+        self.chats.insert(chat.id.clone(), chat);
+    }
+
+    /// The chat a message belongs to: for a group chat the one with its
+    /// GUID, or one with these participants whose GUID isn't known yet;
+    /// otherwise the one with exactly these participants.
+    fn find(&self, sorted: &[String], group_id: Option<&str>) -> Option<&Chat> {
+        if let Some(group_id) = group_id.filter(|_| is_group(sorted)) {
+            if let Some(chat) = self
+                .index
+                .get(&chat_key(sorted, Some(group_id)))
+                .and_then(|id| self.chats.get(id))
+            {
+                return Some(chat);
+            }
+            return self
+                .index
+                .get(&chat_key(sorted, None))
+                .and_then(|id| self.chats.get(id))
+                .filter(|chat| chat.group_id.is_none());
+        }
+        self.index
+            .get(&chat_key(sorted, None))
+            .and_then(|id| self.chats.get(id))
+    }
+}
+
+/// Every conversation the server has sent to or received from, with the
+/// triage metadata operators attach to it. Persisted to
+/// `<data_dir>/chats.json`. A chat keeps the ID it was created with, so
+/// labels, notes and assignees clients attached to it stay valid. New chats,
+/// newly learned group GUIDs and operator changes are saved right away;
+/// activity on known chats is saved by `flush`, every `FLUSH_INTERVAL` and
+/// at shutdown.
+pub struct Chats {
+    path: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+impl Chats {
+    pub fn load(data_dir: &str) -> anyhow::Result<Self> {
+        let path = PathBuf::from_str(data_dir).unwrap().join("chats.json");
+        let chats: Vec<Chat> = persist::load_json(&path)?;
+        let mut inner = Inner::default();
+        for chat in chats {
+            inner.insert(chat);
+        }
+        Ok(Self {
+            path,
+            inner: Mutex::new(inner),
+        })
+    }
+
+    /// Record a message sent or received in its conversation, creating the
+    /// chat on first sight. Returns the chat ID.
+    pub async fn touch(&self, message: &PipelineMessage) -> anyhow::Result<String> {
+        let group_id = message.group_id.as_deref();
+        let group_name = message.group_name.as_deref();
+        let sorted = sorted(&message.participants);
+
+        let mut inner = self.inner.lock().await;
+        let mut learned = false;
+        let mut chat = match inner.find(&sorted, group_id).cloned() {
+            Some(mut chat) => {
+                chat.last_activity = now_secs();
+                if chat.group_id.is_none() && group_id.is_some() {
+                    chat.group_id = group_id.map(str::to_string);
+                    learned = true;
+                }
+                if group_name.is_some() {
+                    chat.group_name = group_name.map(str::to_string);
+                }
+                chat
+            }
+            None => {
+                learned = true;
+                Chat {
+                    id: chat_id(&sorted, group_id),
+                    participants: sorted,
+                    group_id: group_id.map(str::to_string),
+                    group_name: group_name.map(str::to_string),
+                    labels: BTreeSet::new(),
+                    assignee: None,
+                    notes: vec![],
+                    last_activity: now_secs(),
+                    last_active: HashMap::new(),
+                    last_message_id: None,
+                }
+            }
+        };
+        chat.last_message_id = Some(message.id.clone());
+        if let Some(sender) = &message.sender {
+            chat.last_active.insert(sender.clone(), chat.last_activity);
+        }
+        let id = chat.id.clone();
+        inner.insert(chat);
+        // The ID has to survive a restart the moment it is handed out; the
+        // rest can wait for the next flush
+        if learned {
+            self.save(&mut inner).await?;
+        } else {
+            inner.dirty = true;
+        }
+        Ok(id)
+    }
+
+    /// Save activity recorded since the last save, if there is any.
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().await;
+        if !inner.dirty {
+            return Ok(());
+        }
+        self.save(&mut inner).await
+    }
+
+    /// The ID of the chat a message belongs to: the existing chat's, or the
+    /// one `touch` will create for it.
+    pub async fn id_for(&self, message: &PipelineMessage) -> String {
+        let group_id = message.group_id.as_deref();
+        let sorted = sorted(&message.participants);
+        let inner = self.inner.lock().await;
+        match inner.find(&sorted, group_id) {
+            Some(chat) => chat.id.clone(),
+            None => chat_id(&sorted, group_id),
+        }
+    }
+
+    /// What's known about the conversation with these participants (or this
+    /// group GUID): its GUID and latest message, to thread a new send into it.
+    pub async fn thread(
+        &self,
+        participants: &[String],
+        group_id: Option<&str>,
+    ) -> (Option<String>, Option<String>) {
+        let inner = self.inner.lock().await;
+        match inner.find(&sorted(participants), group_id) {
+            Some(chat) => (chat.group_id.clone(), chat.last_message_id.clone()),
+            None => (None, None),
+        }
+    }
+
+    pub async fn get(&self, id: &str) -> Option<Chat> {
+        self.inner.lock().await.chats.get(id).cloned()
+    }
+
+    /// Chats matching `filter`, most recently active first.
+    pub async fn list(&self, filter: &ChatFilter) -> Vec<Chat> {
+        let mut chats: Vec<Chat> = self
+            .inner
+            .lock()
+            .await
+            .chats
+            .values()
+            .filter(|c| filter.matches(c))
+            .cloned()
+            .collect();
+        chats.sort_by(|a, b| b.last_activity.cmp(&a.last_activity));
+        chats
+    }
+
+    /// Apply `change` to the chat with `id` and persist it. Returns the
+    /// updated chat, or `None` if there is no such chat.
+    pub async fn update(
+        &self,
+        id: &str,
+        change: impl FnOnce(&mut Chat),
+    ) -> anyhow::Result<Option<Chat>> {
+        let mut inner = self.inner.lock().await;
+        let Some(chat) = inner.chats.get_mut(id) else {
+            return Ok(None);
+        };
+        change(chat);
+        let chat = chat.clone();
+        self.save(&mut inner).await?;
+        Ok(Some(chat))
+    }
+
+    async fn save(&self, inner: &mut Inner) -> anyhow::Result<()> {
+        let chats: Vec<&Chat> = inner.chats.values().collect();
+        persist::write_atomic(&self.path, &serde_json::to_vec(&chats)?).await?;
+        inner.dirty = false;
+        Ok(())
+    }
+}
+
+/// Save chat activity every `FLUSH_INTERVAL`.
+pub async fn run(state: Arc<AppState>) {
+    loop {
+        tokio::time::sleep(FLUSH_INTERVAL).await;
+        if let Err(e) = state.chats.flush().await {
+            log::warn!("Failed to save chats: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::Direction;
+
+    const ME: &str = "tel:+15550000000";
+    const ALICE: &str = "tel:+15551111111";
+    const BOB: &str = "mailto:bob@example.com";
+
+    fn handles(handles: &[&str]) -> Vec<String> {
+        handles.iter().map(|h| h.to_string()).collect()
+    }
+
+    fn message(participants: &[&str], group_id: Option<&str>) -> PipelineMessage {
+        PipelineMessage {
+            direction: Direction::Inbound,
+            id: Uuid::new_v4().to_string().to_uppercase(),
+            sender: Some(participants[0].to_string()),
+            participants: handles(participants),
+            group_id: group_id.map(str::to_string),
+            group_name: None,
+            text: None,
+            chat_id: None,
+            from_me: false,
+            annotations: HashMap::new(),
+        }
+    }
+
+    fn temp_dir() -> String {
+        let dir = std::env::temp_dir().join(format!("chats-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn chat_id_ignores_participant_order_and_duplicates() {
+        assert_eq!(
+            chat_id(&handles(&[ME, ALICE]), None),
+            chat_id(&handles(&[ALICE, ME, ALICE]), None)
+        );
+        assert_ne!(
+            chat_id(&handles(&[ME, ALICE]), None),
+            chat_id(&handles(&[ME, BOB]), None)
+        );
+    }
+
+    #[test]
+    fn chat_id_of_one_on_one_ignores_group_id() {
+        assert_eq!(
+            chat_id(&handles(&[ME, ALICE]), Some("GUID-1")),
+            chat_id(&handles(&[ME, ALICE]), None)
+        );
+    }
+
+    #[test]
+    fn chat_id_of_group_follows_group_id() {
+        let participants = handles(&[ME, ALICE, BOB]);
+        assert_eq!(
+            chat_id(&participants, Some("GUID-1")),
+            chat_id(&handles(&[BOB, ME, ALICE]), Some("GUID-1"))
+        );
+        assert_ne!(
+            chat_id(&participants, Some("GUID-1")),
+            chat_id(&participants, Some("GUID-2"))
+        );
+        assert_ne!(
+            chat_id(&participants, Some("GUID-1")),
+            chat_id(&participants, None)
+        );
+    }
+
+    #[tokio::test]
+    async fn touch_keeps_one_on_one_id_once_guid_is_known() {
+        let chats = Chats::load(&temp_dir()).unwrap();
+        let first = chats.touch(&message(&[ALICE, ME], None)).await.unwrap();
+        let second = chats
+            .touch(&message(&[ME, ALICE], Some("GUID-1")))
+            .await
+            .unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first, chat_id(&handles(&[ME, ALICE]), None));
+    }
+
+    #[tokio::test]
+    async fn touch_keeps_group_id_stable() {
+        let chats = Chats::load(&temp_dir()).unwrap();
+        let first = chats
+            .touch(&message(&[ALICE, ME, BOB], None))
+            .await
+            .unwrap();
+        // The GUID arrives later; the chat is adopted, not re-keyed
+        let second = chats
+            .touch(&message(&[ALICE, ME, BOB], Some("GUID-1")))
+            .await
+            .unwrap();
+        assert_eq!(first, second);
+
+        // Another group with the same people is a different chat
+        let other = chats
+            .touch(&message(&[ALICE, ME, BOB], Some("GUID-2")))
+            .await
+            .unwrap();
+        assert_ne!(first, other);
+        assert_eq!(
+            chats
+                .id_for(&message(&[BOB, ALICE, ME], Some("GUID-1")))
+                .await,
+            first
+        );
+    }
+
+    #[tokio::test]
+    async fn ids_survive_reload() {
+        let dir = temp_dir();
+        let chats = Chats::load(&dir).unwrap();
+        let one_on_one = chats.touch(&message(&[ALICE, ME], None)).await.unwrap();
+        let group = chats
+            .touch(&message(&[ALICE, ME, BOB], None))
+            .await
+            .unwrap();
+        chats
+            .touch(&message(&[ALICE, ME, BOB], Some("GUID-1")))
+            .await
+            .unwrap();
+
+        let reloaded = Chats::load(&dir).unwrap();
+        assert_eq!(
+            reloaded
+                .id_for(&message(&[ME, ALICE], Some("GUID-9")))
+                .await,
+            one_on_one
+        );
+        assert_eq!(
+            reloaded
+                .id_for(&message(&[ME, ALICE, BOB], Some("GUID-1")))
+                .await,
+            group
+        );
+    }
+
+    #[tokio::test]
+    async fn activity_is_saved_on_flush() {
+        let dir = temp_dir();
+        let chats = Chats::load(&dir).unwrap();
+        let id = chats.touch(&message(&[ALICE, ME], None)).await.unwrap();
+        let latest = message(&[ALICE, ME], None);
+        chats.touch(&latest).await.unwrap();
+
+        let saved = Chats::load(&dir).unwrap().get(&id).await.unwrap();
+        assert_ne!(saved.last_message_id, Some(latest.id.clone()));
+        chats.flush().await.unwrap();
+        let saved = Chats::load(&dir).unwrap().get(&id).await.unwrap();
+        assert_eq!(saved.last_message_id, Some(latest.id));
+    }
+}
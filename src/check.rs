@@ -0,0 +1,266 @@
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::autoresponder::AutoResponder;
+use crate::broadcast::Broadcasts;
+use crate::chats::Chats;
+use crate::inbox::Inbox;
+use crate::messages::Messages;
+use crate::notes::MessageNotes;
+use crate::outbox::Outbox;
+use crate::session;
+use crate::topics::{self, Topics};
+
+/// Numeric settings, each with the range it must parse into; a value that
+/// doesn't parse silently falls back to the default at startup, so `check`
+/// flags it instead.
+const NUMERIC_VARS: [(&str, Range); 12] = [
+    ("IMESSAGE_API_PORT", Range::Port),
+    ("IMESSAGE_BROADCAST_CONCURRENCY", Range::Count),
+    ("IMESSAGE_BROADCAST_MAX_CONCURRENCY", Range::Count),
+    ("IMESSAGE_BROADCAST_INTERVAL_MS", Range::Count),
+    ("IMESSAGE_AUTORESPONDER_COOLDOWN_SECS", Range::Count),
+    ("IMESSAGE_OUTBOX_MAX", Range::Count),
+    ("IMESSAGE_OUTBOX_TTL_SECS", Range::Count),
+    ("IMESSAGE_SLOW_SEND_MS", Range::Count),
+    ("IMESSAGE_BACKOFF_BASE_SECS", Range::Count),
+    ("IMESSAGE_BACKOFF_MAX_SECS", Range::Count),
+    ("IMESSAGE_CHATBOT_CONTEXT", Range::Count),
+    ("IMESSAGE_CHATBOT_TIMEOUT_SECS", Range::Count),
+];
+
+/// The types `main` parses numeric settings into.
+#[derive(Clone, Copy)]
+enum Range {
+    /// `u16`
+    Port,
+    /// `u64` or `usize`
+    Count,
+}
+
+impl Range {
+    fn accepts(self, value: &str) -> bool {
+        match self {
+            Range::Port => u16::from_str(value).is_ok(),
+            Range::Count => u64::from_str(value).is_ok() && usize::from_str(value).is_ok(),
+        }
+    }
+
+    fn hint(self) -> &'static str {
+        match self {
+            Range::Port => "Set it to a port from 0 to 65535 or unset it to use the default",
+            Range::Count => "Set it to a non-negative integer or unset it to use the default",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    failures: usize,
+}
+
+impl Report {
+    fn ok(&self, what: &str) {
+        println!("ok    {}", what);
+    }
+
+    fn warn(&self, what: &str, hint: &str) {
+        println!("warn  {}\n      {}", what, hint);
+    }
+
+    fn fail(&mut self, what: &str, hint: &str) {
+        self.failures += 1;
+        println!("FAIL  {}\n      {}", what, hint);
+    }
+}
+
+/// `imessage-api check`: validate the configuration and the session files in
+/// `data_dir` without connecting to Apple. Nothing in the data dir is
+/// modified. Returns whether everything needed to start is in place.
+pub fn run(data_dir: &str) -> bool {
+    let mut report = Report::default();
+    check_config(&mut report);
+    if check_session(&mut report, data_dir) {
+        check_stores(&mut report, data_dir);
+    }
+
+    if report.failures == 0 {
+        println!("\nAll checks passed");
+        true
+    } else {
+        println!("\n{} check(s) failed", report.failures);
+        false
+    }
+}
+
+fn check_config(report: &mut Report) {
+    for (name, range) in NUMERIC_VARS {
+        let Ok(value) = std::env::var(name) else {
+            continue;
+        };
+        if range.accepts(&value) {
+            report.ok(name);
+        } else {
+            report.fail(
+                &format!("{} = {:?} is not valid", name, value),
+                range.hint(),
+            );
+        }
+    }
+
+    if let Ok(file) = std::env::var("IMESSAGE_API_KEYS_FILE") {
+        match std::fs::read_to_string(&file) {
+            Ok(_) => report.ok("IMESSAGE_API_KEYS_FILE"),
+            Err(e) => report.fail(
+                &format!("IMESSAGE_API_KEYS_FILE {}: {}", file, e),
+                "The key file must exist and be readable at startup",
+            ),
+        }
+    } else if std::env::var("IMESSAGE_API_KEY")
+        .unwrap_or_default()
+        .is_empty()
+    {
+        report.warn(
+            "No API key configured",
+            "Anyone who can reach the port can send messages; set IMESSAGE_API_KEY",
+        );
+    }
+}
+
+/// Returns whether the data dir exists.
+fn check_session(report: &mut Report, data_dir: &str) -> bool {
+    if !Path::new(data_dir).is_dir() {
+        report.fail(
+            &format!("Data dir {} does not exist", data_dir),
+            "Set IMESSAGE_DATA_DIR to the OpenBubbles data directory",
+        );
+        return false;
+    }
+    report.ok(&format!("Data dir {}", data_dir));
+
+    let upload_hint = |name: &str| {
+        format!(
+            "Copy {} from the OpenBubbles data directory again, or upload it with \
+             PUT /api/admin/session/files/{}",
+            name, name
+        )
+    };
+
+    let keystore_loaded = match session::load_keystore_readonly(data_dir) {
+        Ok(()) => {
+            report.ok("keystore.plist");
+            true
+        }
+        Err(e) => {
+            report.fail(
+                &format!("keystore.plist: {}", e),
+                &upload_hint("keystore.plist"),
+            );
+            false
+        }
+    };
+
+    match session::read_hardware(data_dir) {
+        Some(hardware) => {
+            report.ok("hw_info.plist");
+            if keystore_loaded {
+                match session::verify_identity(&hardware) {
+                    Ok(()) => report.ok("Device identity decrypts with the keystore"),
+                    Err(e) => report.fail(
+                        &format!("Device identity can't be decrypted: {}", e),
+                        "hw_info.plist and keystore.plist must come from the same \
+                         OpenBubbles install; copy both again",
+                    ),
+                }
+            }
+        }
+        None => report.fail(
+            "hw_info.plist is missing or can't be parsed",
+            &upload_hint("hw_info.plist"),
+        ),
+    }
+
+    match session::restore_users(data_dir) {
+        Some(users) if users.is_empty() => report.fail(
+            "id.plist has no registered users",
+            "Register your iMessage identity in OpenBubbles first",
+        ),
+        Some(users) => report.ok(&format!("id.plist ({} user(s))", users.len())),
+        None => report.fail(
+            "id.plist is missing or can't be parsed",
+            &upload_hint("id.plist"),
+        ),
+    }
+
+    if !Path::new(data_dir).join("gsa.plist").exists() {
+        report.warn(
+            "gsa.plist is missing",
+            "Sending works without it, but the Apple ID session can't be refreshed",
+        );
+    } else if keystore_loaded {
+        match session::verify_account(data_dir) {
+            Ok(username) => report.ok(&format!("gsa.plist ({})", username)),
+            Err(e) => report.fail(
+                &format!("gsa.plist: {}", e),
+                "The Apple ID password can't be decrypted with this keystore; copy \
+                 gsa.plist and keystore.plist from the same OpenBubbles install",
+            ),
+        }
+    }
+
+    true
+}
+
+/// Load each state file with the loader the server uses at startup, so
+/// anything that would stop it from starting is caught here. The loaders
+/// only read.
+fn check_stores(report: &mut Report, data_dir: &str) {
+    let optional_topics: Vec<String> = std::env::var("IMESSAGE_OPTIONAL_TOPICS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+        .collect();
+    let optional_topics = match topics::validate(&optional_topics) {
+        Ok(()) => optional_topics,
+        Err(e) => {
+            report.fail(
+                &format!("IMESSAGE_OPTIONAL_TOPICS: {}", e),
+                "List only topics the server knows, separated by commas",
+            );
+            vec![]
+        }
+    };
+
+    // Settings that only shape how the stores are used don't matter here
+    let stores = [
+        ("chats.json", Chats::load(data_dir).map(drop)),
+        ("message_notes.json", MessageNotes::load(data_dir).map(drop)),
+        (
+            "outbox.json",
+            Outbox::load(data_dir, 0, Duration::ZERO).map(drop),
+        ),
+        ("inbox", Inbox::load(data_dir).map(drop)),
+        (
+            "topics.json",
+            Topics::load(data_dir, optional_topics).map(drop),
+        ),
+        (
+            "autoresponder.json",
+            AutoResponder::load(data_dir, Duration::ZERO).map(drop),
+        ),
+        ("sent_messages.jsonl", Messages::load(data_dir).map(drop)),
+        ("broadcasts", Broadcasts::load(data_dir, 1, 1, 0).map(drop)),
+    ];
+    for (name, result) in stores {
+        match result {
+            Ok(()) => report.ok(name),
+            Err(e) => report.fail(
+                &format!("{}: {:#}", name, e),
+                "The server won't start until this is repaired or removed",
+            ),
+        }
+    }
+}
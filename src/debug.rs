@@ -0,0 +1,34 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+/// How many decoded messages to keep.
+const CAPACITY: usize = 500;
+
+/// The raw rustpush structure of recently received messages, as printed by
+/// its `Debug` impl, for working out why a message type isn't supported.
+/// Only kept in memory, and only in debug mode (`IMESSAGE_DEBUG`), since it
+/// holds full message contents.
+#[derive(Default)]
+pub struct RawMessages {
+    messages: Mutex<VecDeque<(String, String)>>,
+}
+
+impl RawMessages {
+    pub fn record(&self, id: &str, decoded: String) {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() == CAPACITY {
+            messages.pop_front();
+        }
+        messages.push_back((id.to_string(), decoded));
+    }
+
+    pub fn get(&self, id: &str) -> Option<String> {
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|(message_id, _)| message_id.eq_ignore_ascii_case(id))
+            .map(|(_, decoded)| decoded.clone())
+    }
+}
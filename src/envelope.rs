@@ -0,0 +1,115 @@
+use async_trait::async_trait;
+use axum::extract::{FromRequest, Request};
+use axum::http::{HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::error::AppError;
+
+tokio::task_local! {
+    /// ID of the `/v1` request being handled. Unset under `/api`, whose
+    /// responses keep their bare shapes.
+    static REQUEST_ID: String;
+}
+
+/// The response shape for every `/v1` endpoint. Exactly one of `data` and
+/// `error` is non-null.
+#[derive(Serialize)]
+pub struct Envelope<T> {
+    pub data: Option<T>,
+    pub error: Option<Value>,
+    pub request_id: String,
+}
+
+/// Mark `/v1` requests so their JSON responses are enveloped. The request ID
+/// is taken from `X-Request-Id` when the caller sends one and echoed back in
+/// the same header.
+pub async fn middleware(req: Request, next: Next) -> Response {
+    if !req.uri().path().starts_with("/v1/") {
+        return next.run(req).await;
+    }
+
+    let request_id = req
+        .headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string().to_uppercase());
+
+    let mut response = REQUEST_ID.scope(request_id.clone(), next.run(req)).await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert("x-request-id", value);
+    }
+    response
+}
+
+/// JSON body of a handler's response or request. As a response it is bare
+/// under `/api` and wrapped in an `Envelope` under `/v1`; as a request body,
+/// a payload that doesn't parse is rejected with an `AppError`, so the
+/// error is enveloped too.
+pub struct ApiJson<T>(pub T);
+
+impl<T: Serialize> IntoResponse for ApiJson<T> {
+    fn into_response(self) -> Response {
+        match REQUEST_ID.try_with(Clone::clone) {
+            Ok(request_id) => Json(Envelope {
+                data: Some(self.0),
+                error: None,
+                request_id,
+            })
+            .into_response(),
+            Err(_) => Json(self.0).into_response(),
+        }
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequest<S> for ApiJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(value)) => Ok(Self(value)),
+            Err(rejection) => Err(AppError::new(
+                rejection.status(),
+                anyhow::anyhow!(rejection.body_text()),
+            )),
+        }
+    }
+}
+
+/// `body` as an error response body: bare under `/api`, the `error` of an
+/// `Envelope` under `/v1`.
+pub fn error_body(body: Value) -> Value {
+    match REQUEST_ID.try_with(Clone::clone) {
+        Ok(request_id) => json!(Envelope::<Value> {
+            data: None,
+            error: Some(body),
+            request_id,
+        }),
+        Err(_) => body,
+    }
+}
+
+/// A bodiless error status under `/api`; under `/v1`, an envelope whose
+/// error is the status's reason phrase. Used for responses that don't come
+/// from a handler, like auth failures and unknown routes.
+pub fn status(status: StatusCode) -> Response {
+    if REQUEST_ID.try_with(|_| ()).is_err() {
+        return status.into_response();
+    }
+    let message = status.canonical_reason().unwrap_or_default();
+    (status, Json(error_body(json!({ "error": message })))).into_response()
+}
+
+pub async fn not_found() -> Response {
+    status(StatusCode::NOT_FOUND)
+}
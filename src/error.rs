@@ -2,21 +2,87 @@ use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use serde_json::json;
 
-pub struct AppError(pub anyhow::Error);
+use crate::backoff::CoolingDown;
+use crate::envelope;
+use crate::handlers::InvalidRecipient;
+use crate::outbox::OutboxFull;
+use crate::pipeline::Blocked;
+use crate::send::{
+    DeliveryFailed, DeliveryTimedOut, RecipientNotAllowed, RecipientNotIMessage, SendTimedOut,
+};
+use crate::session::{NoSession, RestartRequired};
+
+pub struct AppError {
+    pub status: StatusCode,
+    pub error: anyhow::Error,
+}
+
+impl AppError {
+    pub fn new(status: StatusCode, error: impl Into<anyhow::Error>) -> Self {
+        Self {
+            status,
+            error: error.into(),
+        }
+    }
+}
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let body = serde_json::to_string(&json!({
-            "error": self.0.to_string()
-        }))
-        .unwrap();
+        let mut body = json!({
+            "error": self.error.to_string()
+        });
+        let mut retry_after = None;
+
+        if let Some(cooldown) = self.error.downcast_ref::<CoolingDown>() {
+            let secs = cooldown.retry_after.as_secs().max(1);
+            body["retry_after_secs"] = json!(secs);
+            retry_after = Some(secs.to_string());
+        }
+        if let Some(err) = self.error.downcast_ref::<RecipientNotIMessage>() {
+            body["code"] = json!("recipient_not_imessage");
+            body["handle"] = json!(err.handles.first());
+            if err.handles.len() > 1 {
+                body["handles"] = json!(err.handles);
+            }
+        }
+        if let Some(err) = self.error.downcast_ref::<SendTimedOut>() {
+            body["code"] = json!("send_timeout");
+            body["message_id"] = json!(err.message_id);
+        }
+        if self.error.is::<NoSession>() {
+            body["code"] = json!("session_unavailable");
+        }
+        if self.error.is::<OutboxFull>() {
+            body["code"] = json!("outbox_full");
+        }
+        if self.error.is::<RestartRequired>() {
+            body["code"] = json!("restart_required");
+        }
+        if let Some(err) = self.error.downcast_ref::<DeliveryFailed>() {
+            body["code"] = json!("delivery_failed");
+            body["message_id"] = json!(err.message_id);
+        }
+        if let Some(err) = self.error.downcast_ref::<DeliveryTimedOut>() {
+            body["code"] = json!("delivery_timeout");
+            body["message_id"] = json!(err.message_id);
+        }
+        if self.error.is::<InvalidRecipient>() {
+            body["code"] = json!("invalid_recipient");
+        }
+        if let Some(err) = self.error.downcast_ref::<RecipientNotAllowed>() {
+            body["code"] = json!("recipient_not_allowed");
+            body["handles"] = json!(err.handles);
+        }
 
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            [("content-type", "application/json")],
-            body,
-        )
-            .into_response()
+        let body = serde_json::to_string(&envelope::error_body(body)).unwrap();
+        let mut response =
+            (self.status, [("content-type", "application/json")], body).into_response();
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert("retry-after", retry_after.parse().unwrap());
+        }
+        response
     }
 }
 
@@ -25,6 +91,24 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(err: E) -> Self {
-        Self(err.into())
+        let error = err.into();
+        let status = if error.is::<Blocked>() || error.is::<RecipientNotAllowed>() {
+            StatusCode::FORBIDDEN
+        } else if error.is::<InvalidRecipient>() {
+            StatusCode::BAD_REQUEST
+        } else if error.is::<CoolingDown>() || error.is::<NoSession>() || error.is::<OutboxFull>() {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else if error.is::<SendTimedOut>() || error.is::<DeliveryTimedOut>() {
+            StatusCode::GATEWAY_TIMEOUT
+        } else if error.is::<DeliveryFailed>() {
+            StatusCode::BAD_GATEWAY
+        } else if error.is::<RecipientNotIMessage>() {
+            StatusCode::UNPROCESSABLE_ENTITY
+        } else if error.is::<RestartRequired>() {
+            StatusCode::CONFLICT
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        };
+        Self { status, error }
     }
 }
@@ -1,97 +1,1148 @@
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use axum::extract::State;
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
-use axum::response::IntoResponse;
-use axum::Json;
-use log::info;
-use rustpush::{ConversationData, IMClient, Message, MessageInst, MessageType, NormalMessage};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use rustpush::{Message, MessageType, NormalMessage};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio_stream::wrappers::ReceiverStream;
 
+use crate::autoresponder::AutoResponder;
+use crate::backoff::Backoff;
+use crate::broadcast::{self, BroadcastHandle, BroadcastJob, Broadcasts};
+use crate::chatbot::Chatbot;
+use crate::chats::{now_secs, ChatFilter, Chats};
+use crate::debug::RawMessages;
+use crate::envelope::ApiJson;
 use crate::error::AppError;
-use crate::types::{HandlesResponse, HealthResponse, SendRequest, SendResponse};
+use crate::inbound;
+use crate::inbox::Inbox;
+use crate::logging::LogFilter;
+use crate::messages::Messages;
+use crate::metrics::Metrics;
+use crate::notes::{MessageNotes, Note};
+use crate::outbox::{self, Outbox};
+use crate::persist::{self, Writer};
+use crate::pipeline::{Direction, Pipeline, PipelineMessage};
+use crate::send;
+use crate::session::{self, NoSession, Session};
+use crate::topics::{self, Topics};
+use crate::types::{
+    ApsStatus, AssigneeRequest, BroadcastCreatedResponse, BroadcastRequest, ChatParticipant,
+    ChatToggleRequest, ChatsQuery, ChatsResponse, CreateRuleRequest, DryRunResponse, HandleDetails,
+    HandleKind, HandlesResponse, HealthResponse, IndividualSendRequest, IndividualSendResponse,
+    IndividualSendResult, InjectRequest, InjectResponse, LabelsRequest, LogLevel, NormalizeQuery,
+    NormalizeResponse, NoteRequest, NotesResponse, ParticipantsResponse, QueueStatus,
+    RawMessageResponse, RegistrationState, RulesResponse, SendQuery, SendRequest, SendResponse,
+    SessionFile, SessionFilesResponse, SessionStatus, ShutdownRequest, SlowSendsResponse,
+    StatusResponse, TopicsRequest, TopicsResponse,
+};
 
 pub struct AppState {
-    pub client: Arc<IMClient>,
+    /// `None` until a session has been restored successfully.
+    pub session: RwLock<Option<Arc<Session>>>,
+    /// Why the last restore failed, if it did.
+    pub restore_error: RwLock<Option<String>>,
+    /// Held while a session restore is in progress so restores don't overlap.
+    pub reloading: Mutex<()>,
+    /// Notified to stop the HTTP server.
+    pub shutdown: Notify,
+    pub data_dir: String,
+    /// Sandbox mode: when set, sends to any other handle are refused.
+    pub allowed_recipients: Option<HashSet<String>>,
+    pub log_filter: Arc<LogFilter>,
+    pub started_at: Instant,
+    pub metrics: Arc<Metrics>,
+    pub backoff: Backoff,
+    pub broadcasts: Broadcasts,
+    pub pipeline: Pipeline,
+    pub autoresponder: AutoResponder,
+    pub chatbot: Option<Arc<Chatbot>>,
+    pub chats: Chats,
+    pub message_notes: MessageNotes,
+    pub inbox: Inbox,
+    pub messages: Messages,
+    pub outbox: Outbox,
+    pub topics: Topics,
+    /// Writes session state (keys, push token) off the async hot path.
+    pub writer: Writer,
+    /// Set in debug mode (`IMESSAGE_DEBUG`).
+    pub raw_messages: Option<RawMessages>,
 }
 
-fn format_phone(number: &str) -> String {
+impl AppState {
+    /// The current session. Hold on to it only for the duration of one
+    /// operation; it may be replaced by a reload at any time.
+    pub fn session(&self) -> Result<Arc<Session>, NoSession> {
+        self.session.read().unwrap().clone().ok_or(NoSession)
+    }
+}
+
+/// A recipient that is neither an email address nor has any digits to make
+/// a phone number from.
+#[derive(Debug)]
+pub struct InvalidRecipient {
+    pub input: String,
+}
+
+impl fmt::Display for InvalidRecipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} is not a phone number or email address", self.input)
+    }
+}
+
+impl std::error::Error for InvalidRecipient {}
+
+pub fn format_phone(number: &str) -> Result<String, InvalidRecipient> {
+    let input = number;
+    let number = number.trim();
+    if number.starts_with("mailto:") {
+        return Ok(number.to_string());
+    }
+    if number.contains('@') {
+        return Ok(format!("mailto:{}", number));
+    }
+    // Separators are dropped; without a `+` a 10-digit number is taken to
+    // be a US one
+    let number = number.strip_prefix("tel:").unwrap_or(number);
     let digits: String = number.chars().filter(|c| c.is_ascii_digit()).collect();
-    if digits.len() == 10 {
-        format!("tel:+1{}", digits)
-    } else if digits.len() == 11 && digits.starts_with('1') {
-        format!("tel:+{}", digits)
-    } else if number.starts_with("tel:") {
-        number.to_string()
-    } else if number.starts_with('+') {
-        format!("tel:{}", number)
+    if digits.is_empty() {
+        return Err(InvalidRecipient {
+            input: input.to_string(),
+        });
+    }
+    if digits.len() == 10 && !number.starts_with('+') {
+        Ok(format!("tel:+1{}", digits))
     } else {
-        format!("tel:+{}", digits)
+        Ok(format!("tel:+{}", digits))
+    }
+}
+
+/// Parse a duration like `10s`, `500ms` or `2m`; a bare number is seconds.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Some(ms) = value.strip_suffix("ms") {
+        return ms.parse().ok().map(Duration::from_millis);
     }
+    if let Some(mins) = value.strip_suffix('m') {
+        return mins
+            .parse::<u64>()
+            .ok()
+            .map(|m| Duration::from_secs(m * 60));
+    }
+    let secs = value.strip_suffix('s').unwrap_or(value);
+    secs.parse().ok().map(Duration::from_secs)
+}
+
+/// Anything about `input` that suggests `format_phone` may not have produced
+/// the handle the caller meant.
+pub fn normalize_warnings(input: &str, handle: &str) -> Vec<String> {
+    let mut warnings = vec![];
+    let input = input.trim();
+
+    if let Some(address) = handle.strip_prefix("mailto:") {
+        let valid = match address.split_once('@') {
+            Some((user, domain)) => {
+                !user.is_empty() && domain.contains('.') && !domain.contains('@')
+            }
+            None => false,
+        };
+        if !valid {
+            warnings.push(format!("{} does not look like an email address", address));
+        }
+        return warnings;
+    }
+
+    let number = input.strip_prefix("tel:").unwrap_or(input);
+    if number.chars().any(|c| c.is_alphabetic()) {
+        warnings.push("Letters in the input were ignored".to_string());
+    }
+    let digits: String = number.chars().filter(|c| c.is_ascii_digit()).collect();
+    if digits.len() == 10 && !number.starts_with('+') {
+        warnings.push("No country code given; assumed US (+1)".to_string());
+    }
+    let e164 = handle.trim_start_matches("tel:+");
+    if !(8..=15).contains(&e164.len()) || !e164.chars().all(|c| c.is_ascii_digit()) {
+        warnings.push(format!(
+            "{} is not a valid E.164 number (expected 8 to 15 digits)",
+            handle
+        ));
+    }
+    warnings
+}
+
+pub async fn normalize(
+    Query(query): Query<NormalizeQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let handle = format_phone(&query.to)?;
+    let warnings = normalize_warnings(&query.to, &handle);
+    Ok(ApiJson(NormalizeResponse {
+        input: query.to,
+        handle,
+        warnings,
+    }))
 }
 
 pub async fn send_message(
     State(state): State<Arc<AppState>>,
-    Json(req): Json<SendRequest>,
-) -> Result<impl IntoResponse, AppError> {
-    let handles = state.client.identity.get_handles().await;
-    let sender = handles
-        .first()
-        .ok_or_else(|| anyhow::anyhow!("No registered handles"))?
-        .clone();
+    Query(query): Query<SendQuery>,
+    ApiJson(req): ApiJson<SendRequest>,
+) -> Result<Response, AppError> {
+    let wait_delivered = match query.wait.as_deref() {
+        None => None,
+        Some("delivered") => Some(match &query.timeout {
+            Some(timeout) => parse_duration(timeout).ok_or_else(|| {
+                AppError::new(
+                    StatusCode::BAD_REQUEST,
+                    anyhow::anyhow!("Invalid timeout {:?}; use e.g. 10s or 500ms", timeout),
+                )
+            })?,
+            None => Duration::from_secs(10),
+        }),
+        Some(other) => {
+            return Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                anyhow::anyhow!(
+                    "Unsupported wait {:?}; only \"delivered\" is supported",
+                    other
+                ),
+            ))
+        }
+    };
 
-    let to = format_phone(&req.to);
-    info!("Sending message to {} (formatted: {}) from {}", req.to, to, sender);
+    if req.dry_run {
+        let normal = NormalMessage::new(req.message, MessageType::IMessage);
+        let plan = send::dry_run(
+            &state,
+            send::Target::handle(&req.to)?,
+            Message::Message(normal),
+        )
+        .await?;
+        return Ok(ApiJson(DryRunResponse {
+            dry_run: true,
+            sender: plan.sender,
+            recipients: plan.recipients,
+            message: plan.text,
+        })
+        .into_response());
+    }
 
-    let conversation = ConversationData {
-        participants: vec![sender.clone(), to],
-        cv_name: None,
-        sender_guid: None,
-        after_guid: None,
+    let message_id = match &req.message_id {
+        Some(id) => {
+            let id = uuid::Uuid::parse_str(id)
+                .map_err(|_| {
+                    AppError::new(
+                        StatusCode::BAD_REQUEST,
+                        anyhow::anyhow!("message_id must be a UUID"),
+                    )
+                })?
+                .to_string()
+                .to_uppercase();
+            if !state.messages.claim(&id).await {
+                return Err(AppError::new(
+                    StatusCode::CONFLICT,
+                    anyhow::anyhow!("message_id {} has already been used", id),
+                ));
+            }
+            Some(id)
+        }
+        None => None,
     };
 
-    let normal = NormalMessage::new(req.message.clone(), MessageType::IMessage);
-    let mut msg = MessageInst::new(conversation, &sender, Message::Message(normal));
-    let message_id = msg.id.clone();
+    let claimed = message_id.is_some();
+    let message_id = message_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string().to_uppercase());
 
-    let result = state.client.send(&mut msg).await?;
+    let options = send::SendOptions {
+        message_id: Some(message_id.clone()),
+        timeout: req.timeout_ms.map(Duration::from_millis),
+        wait_delivered,
+        ..Default::default()
+    };
+    let result = send::send_text(&state, &req.to, &req.message, options).await;
 
-    if let Some(handle) = result.handle {
-        let uuid = message_id.clone();
-        tokio::spawn(async move {
-            match handle.await {
-                Ok(Ok(())) => info!("Message {} delivered", uuid),
-                Ok(Err(e)) => log::warn!("Message {} delivery error: {}", uuid, e),
-                Err(e) => log::warn!("Message {} join error: {}", uuid, e),
+    // Connection to Apple is down: accept the message and send it once it's
+    // back, unless the caller is waiting on delivery
+    if let Err(e) = &result {
+        if wait_delivered.is_none() && outbox::should_queue(&state, e) {
+            log::warn!(
+                "Queueing message {} until the connection recovers: {}",
+                message_id,
+                e
+            );
+            // The outbox holds the claim until the send goes out or is dropped
+            let queued = state
+                .outbox
+                .push(outbox::queued(message_id.clone(), &req.to, &req.message))
+                .await;
+            if let Err(e) = queued {
+                if claimed {
+                    state.messages.release(&message_id).await;
+                }
+                return Err(e.into());
             }
-        });
+            return Ok((
+                StatusCode::ACCEPTED,
+                ApiJson(SendResponse {
+                    success: true,
+                    message_id,
+                    delivered: None,
+                    queued: true,
+                }),
+            )
+                .into_response());
+        }
+    }
+
+    // A timed-out send may have gone out; `send` recorded its ID instead
+    let timed_out = result.as_ref().is_err_and(|e| e.is::<send::SendTimedOut>());
+    if claimed && !timed_out {
+        state.messages.release(&message_id).await;
     }
+    let sent = result?;
 
     Ok((
         StatusCode::OK,
-        Json(SendResponse {
+        ApiJson(SendResponse {
             success: true,
-            message_id,
+            message_id: sent.message_id,
+            delivered: wait_delivered.map(|_| sent.delivered),
+            queued: false,
         }),
-    ))
+    )
+        .into_response())
+}
+
+/// Most recipients `/send/individual` takes in one request; larger lists
+/// belong in a broadcast.
+const MAX_INDIVIDUAL_RECIPIENTS: usize = 50;
+
+/// Send the same text to each recipient as its own one-on-one conversation,
+/// one after another, and report how each send went.
+pub async fn send_individual(
+    State(state): State<Arc<AppState>>,
+    ApiJson(req): ApiJson<IndividualSendRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    if req.to.is_empty() || req.to.len() > MAX_INDIVIDUAL_RECIPIENTS {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!(
+                "to must list 1 to {} recipients; use /api/broadcast for more",
+                MAX_INDIVIDUAL_RECIPIENTS
+            ),
+        ));
+    }
+
+    // The same person listed twice, e.g. with and without a country code,
+    // gets one message
+    let mut recipients: Vec<String> = vec![];
+    for to in &req.to {
+        let to = format_phone(to)?;
+        if !recipients.contains(&to) {
+            recipients.push(to);
+        }
+    }
+
+    let mut results = vec![];
+    for to in recipients {
+        let result = send::send_text(&state, &to, &req.message, send::SendOptions::default()).await;
+        results.push(match result {
+            Ok(sent) => IndividualSendResult {
+                to,
+                success: true,
+                message_id: Some(sent.message_id),
+                error: None,
+            },
+            Err(e) => IndividualSendResult {
+                to,
+                success: false,
+                message_id: None,
+                error: Some(e.to_string()),
+            },
+        });
+    }
+
+    let sent = results.iter().filter(|r| r.success).count();
+    Ok(ApiJson(IndividualSendResponse {
+        sent,
+        failed: results.len() - sent,
+        results,
+    }))
 }
 
 pub async fn get_handles(
     State(state): State<Arc<AppState>>,
 ) -> Result<impl IntoResponse, AppError> {
-    let handles = state.client.identity.get_handles().await.to_vec();
-    Ok(Json(HandlesResponse { handles }))
+    let handles = state.session()?.client.identity.get_handles().await.to_vec();
+    let registrations = session::read_registrations(&state.data_dir);
+    let now = now_secs();
+
+    let details = handles
+        .iter()
+        .enumerate()
+        .map(|(i, handle)| {
+            let registration = registrations.iter().find(|r| r.handles.contains(handle));
+            let registration_state = match registration {
+                None => RegistrationState::Unknown,
+                Some(r) if r.expires_at.is_some_and(|at| at <= now) => RegistrationState::Expired,
+                Some(_) => RegistrationState::Registered,
+            };
+            HandleDetails {
+                handle: handle.clone(),
+                kind: if handle.starts_with("mailto:") {
+                    HandleKind::Email
+                } else {
+                    HandleKind::Phone
+                },
+                registration: registration_state,
+                registered_at: registration.and_then(|r| r.registered_at),
+                expires_at: registration.and_then(|r| r.expires_at),
+                // send::prepare always sends from the first handle
+                default: i == 0,
+            }
+        })
+        .collect();
+
+    Ok(ApiJson(HandlesResponse { handles, details }))
 }
 
 pub async fn health(
     State(state): State<Arc<AppState>>,
 ) -> Result<impl IntoResponse, AppError> {
-    let handles = state.client.identity.get_handles().await;
-    let status = if handles.is_empty() {
-        "no_handles"
-    } else {
-        "ok"
+    let status = match state.session() {
+        Err(_) => "no_session",
+        Ok(session) if session.client.identity.get_handles().await.is_empty() => "no_handles",
+        Ok(_) => "ok",
     };
-    Ok(Json(HealthResponse {
+    Ok(ApiJson(HealthResponse {
         status: status.to_string(),
     }))
 }
+
+/// Readiness probe: 200 once a session is live, 503 while it's still being
+/// restored or after the restore failed.
+pub async fn readyz(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    if state.session().is_ok() {
+        return (
+            StatusCode::OK,
+            ApiJson(HealthResponse {
+                status: "ready".to_string(),
+            }),
+        );
+    }
+    let status = if state.restore_error.read().unwrap().is_some() {
+        "restore_failed"
+    } else {
+        "starting"
+    };
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        ApiJson(HealthResponse {
+            status: status.to_string(),
+        }),
+    )
+}
+
+pub async fn status(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let (handles, has_token) = match state.session() {
+        Ok(session) => (
+            session.client.identity.get_handles().await.to_vec(),
+            session.conn.state.read().await.token.is_some(),
+        ),
+        Err(_) => (vec![], false),
+    };
+    let restore_error = state.restore_error.read().unwrap().clone();
+    let (last_send_at, last_receive_at) = state.metrics.last_activity();
+    let (broadcasts_running, broadcast_recipients_pending) = state.broadcasts.backlog().await;
+
+    Ok(ApiJson(StatusResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        data_dir: state.data_dir.clone(),
+        handles,
+        session: SessionStatus {
+            ready: state.session().is_ok(),
+            restore_error,
+        },
+        aps: ApsStatus { has_token },
+        backoff_remaining_secs: state.backoff.remaining().map(|d| d.as_secs().max(1)),
+        last_send_at,
+        last_receive_at,
+        queues: QueueStatus {
+            broadcasts_running,
+            broadcast_recipients_pending,
+            outbox_pending: state.outbox.len().await,
+        },
+    }))
+}
+
+pub async fn get_log_level(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    Ok(ApiJson(LogLevel {
+        filter: state.log_filter.spec(),
+    }))
+}
+
+pub async fn set_log_level(
+    State(state): State<Arc<AppState>>,
+    ApiJson(req): ApiJson<LogLevel>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .log_filter
+        .set(&req.filter)
+        .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, e))?;
+    Ok(ApiJson(LogLevel {
+        filter: state.log_filter.spec(),
+    }))
+}
+
+pub async fn reload_session(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    log::info!("Reloading session from {}", state.data_dir);
+    let session = session::activate(&state).await?;
+
+    let handles = session.client.identity.get_handles().await.to_vec();
+    log::info!("Session reloaded with {} handles", handles.len());
+    Ok(ApiJson(HandlesResponse {
+        handles,
+        details: vec![],
+    }))
+}
+
+fn topics_response(state: &AppState) -> TopicsResponse {
+    TopicsResponse {
+        subscribed: state
+            .session()
+            .map(|s| s.topics.clone())
+            .unwrap_or_default(),
+        enabled: state.topics.enabled(),
+        available: topics::OPTIONAL_TOPICS.map(str::to_string).to_vec(),
+    }
+}
+
+pub async fn get_topics(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    Ok(ApiJson(topics_response(&state)))
+}
+
+/// Change the optional topics and restore the session again so the new set
+/// is registered.
+pub async fn set_topics(
+    State(state): State<Arc<AppState>>,
+    ApiJson(req): ApiJson<TopicsRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    topics::validate(&req.enabled).map_err(|e| AppError::new(StatusCode::BAD_REQUEST, e))?;
+    let previous = state.topics.replace(req.enabled)?;
+    log::info!(
+        "Optional topics set to {:?}; reloading session",
+        state.topics.enabled()
+    );
+    // Only keep the new topics once a session registered with them; the
+    // previous session stays active on failure, so its topics do too
+    if let Err(e) = session::activate(&state).await {
+        state.topics.replace(previous)?;
+        return Err(e.into());
+    }
+    state.topics.save().await?;
+    Ok(ApiJson(topics_response(&state)))
+}
+
+pub async fn get_raw_message(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let Some(raw) = &state.raw_messages else {
+        return Err(AppError::new(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("Debug mode is off; set IMESSAGE_DEBUG=1 to record raw messages"),
+        ));
+    };
+    let decoded = raw.get(&id).ok_or_else(|| {
+        AppError::new(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("No raw message {} recorded", id),
+        )
+    })?;
+    Ok(ApiJson(RawMessageResponse { id, decoded }))
+}
+
+/// Feed a made-up incoming message through the same dedupe, inbox,
+/// middleware and chatbot path as a real one. Debug mode only.
+pub async fn inject_message(
+    State(state): State<Arc<AppState>>,
+    ApiJson(req): ApiJson<InjectRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    if state.raw_messages.is_none() {
+        return Err(AppError::new(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("Debug mode is off; set IMESSAGE_DEBUG=1 to inject messages"),
+        ));
+    }
+    let session = state.session()?;
+
+    let sender = format_phone(&req.sender)?;
+    let mut participants = req
+        .participants
+        .iter()
+        .map(|p| format_phone(p))
+        .collect::<Result<Vec<_>, _>>()?;
+    if participants.is_empty() {
+        participants.push(sender.clone());
+        participants.extend(session.client.identity.get_handles().await.first().cloned());
+    }
+    let message = PipelineMessage {
+        direction: Direction::Inbound,
+        id: req
+            .id
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string())
+            .to_uppercase(),
+        sender: Some(sender),
+        participants,
+        group_id: req.group_id,
+        group_name: req.group_name,
+        text: req.text,
+        chat_id: None,
+        from_me: false,
+        annotations: HashMap::new(),
+    };
+    let id = message.id.clone();
+    log::info!("Injecting incoming message {}", id);
+
+    let processed = {
+        let _processing = state.inbox.processing().await;
+        inbound::ingest(&state, &session, message, None).await
+    };
+    Ok(ApiJson(InjectResponse { id, processed }))
+}
+
+pub async fn list_session_files(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let dir = PathBuf::from(&state.data_dir);
+    let mut files = vec![];
+    for name in session::SESSION_FILES {
+        let size = tokio::fs::metadata(dir.join(name)).await.ok().map(|m| m.len());
+        files.push(SessionFile {
+            name: name.to_string(),
+            size,
+        });
+    }
+    Ok(ApiJson(SessionFilesResponse { files }))
+}
+
+pub async fn upload_session_file(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    body: Bytes,
+) -> Result<impl IntoResponse, AppError> {
+    if !session::SESSION_FILES.contains(&name.as_str()) {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!(
+                "Unknown session file {:?}; expected one of {}",
+                name,
+                session::SESSION_FILES.join(", ")
+            ),
+        ));
+    }
+    // The running keystore would keep using, and eventually overwrite, its
+    // own copy of the file
+    if name == "keystore.plist" {
+        return Err(AppError::new(
+            StatusCode::CONFLICT,
+            anyhow::anyhow!(
+                "keystore.plist is loaded once per process; copy it into the data dir and restart the server"
+            ),
+        ));
+    }
+    if let Err(e) = plist::from_bytes::<plist::Value>(&body) {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!("{} is not a valid plist: {}", name, e),
+        ));
+    }
+
+    tokio::fs::create_dir_all(&state.data_dir).await?;
+    persist::write_atomic(&PathBuf::from(&state.data_dir).join(&name), &body).await?;
+    log::info!("Session file {} uploaded ({} bytes)", name, body.len());
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn shutdown(
+    State(state): State<Arc<AppState>>,
+    ApiJson(req): ApiJson<ShutdownRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    if !req.confirm {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!("Pass {{\"confirm\": true}} to shut down the server"),
+        ));
+    }
+    log::warn!("Shutdown requested via API");
+    state.shutdown.notify_one();
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub async fn metrics(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    Ok(ApiJson(state.metrics.snapshot()))
+}
+
+pub async fn slow_sends(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    Ok(ApiJson(SlowSendsResponse {
+        threshold_ms: state.metrics.slow_send_threshold().as_millis() as u64,
+        sends: state.metrics.slow_sends(),
+    }))
+}
+
+pub async fn create_broadcast(
+    State(state): State<Arc<AppState>>,
+    ApiJson(req): ApiJson<BroadcastRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    if req.recipients.is_empty() {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!("recipients must not be empty"),
+        ));
+    }
+    for to in &req.recipients {
+        format_phone(to)?;
+    }
+
+    let job = BroadcastJob::new(
+        req.message,
+        req.recipients,
+        req.concurrency
+            .unwrap_or(state.broadcasts.default_concurrency)
+            .min(state.broadcasts.max_concurrency),
+        req.interval_ms.unwrap_or(state.broadcasts.default_interval_ms),
+    );
+    let response = BroadcastCreatedResponse {
+        broadcast_id: job.id.clone(),
+        total: job.recipients.len(),
+    };
+
+    let job = state.broadcasts.insert(job).await?;
+    tokio::spawn(broadcast::run(state.clone(), job));
+
+    Ok((StatusCode::ACCEPTED, ApiJson(response)))
+}
+
+pub async fn get_broadcast(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let handle = find_broadcast(&state, &id).await?;
+    let status = handle.job.lock().await.status();
+    Ok(ApiJson(status))
+}
+
+/// Server-sent events for a broadcast: a `snapshot` event with the current
+/// status, then one `progress` event per completed recipient. The stream ends
+/// once the job is done.
+pub async fn broadcast_events(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let handle = find_broadcast(&state, &id).await?;
+
+    // Subscribe before taking the snapshot so no update falls in between
+    let mut updates = handle.events.subscribe();
+    let snapshot = handle.job.lock().await.status();
+
+    let (tx, rx) = mpsc::channel(32);
+    tokio::spawn(async move {
+        let done = snapshot.done;
+        if tx
+            .send(Event::default().event("snapshot").json_data(&snapshot))
+            .await
+            .is_err()
+            || done
+        {
+            return;
+        }
+        loop {
+            match updates.recv().await {
+                Ok(update) => {
+                    let done = update.done;
+                    let event = Event::default().event("progress").json_data(&update);
+                    if tx.send(event).await.is_err() || done {
+                        break;
+                    }
+                }
+                // Counts are cumulative, so a slow client just skips ahead
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    Ok(Sse::new(ReceiverStream::new(rx)).keep_alive(KeepAlive::default()))
+}
+
+async fn find_broadcast(state: &AppState, id: &str) -> Result<Arc<BroadcastHandle>, AppError> {
+    state.broadcasts.get(id).await.ok_or_else(|| {
+        AppError::new(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("Broadcast {} not found", id),
+        )
+    })
+}
+
+pub async fn list_rules(
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let (rules, disabled_chats) = state.autoresponder.rules().await;
+    Ok(ApiJson(RulesResponse {
+        rules,
+        disabled_chats,
+    }))
+}
+
+pub async fn create_rule(
+    State(state): State<Arc<AppState>>,
+    ApiJson(req): ApiJson<CreateRuleRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let rule = state
+        .autoresponder
+        .add_rule(
+            req.match_type,
+            req.pattern,
+            req.action,
+            req.enabled.unwrap_or(true),
+        )
+        .await
+        .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, e))?;
+    Ok((StatusCode::CREATED, ApiJson(rule)))
+}
+
+pub async fn delete_rule(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    if !state.autoresponder.remove_rule(&id).await? {
+        return Err(AppError::new(
+            StatusCode::NOT_FOUND,
+            anyhow::anyhow!("Rule {} not found", id),
+        ));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn set_chat_autoresponder(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    ApiJson(req): ApiJson<ChatToggleRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.chats.get(&id).await.ok_or_else(|| chat_not_found(&id))?;
+    state
+        .autoresponder
+        .set_chat_enabled(chat.id, req.enabled)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_chats(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ChatsQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let filter = ChatFilter {
+        label: query.label,
+        assignee: query.assignee,
+        unassigned: query.unassigned,
+    };
+    let chats = state.chats.list(&filter).await;
+    Ok(ApiJson(ChatsResponse { chats }))
+}
+
+pub async fn get_chat_participants(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.chats.get(&id).await.ok_or_else(|| chat_not_found(&id))?;
+
+    // iMessage availability comes from one IDS lookup for everyone else in
+    // the chat, made from our handle in it
+    let mut own_handles = vec![];
+    let mut reachable = None;
+    if let Ok(session) = state.session() {
+        own_handles = session.client.identity.get_handles().await.to_vec();
+        let sender = chat
+            .participants
+            .iter()
+            .find(|p| own_handles.contains(p))
+            .or(own_handles.first());
+        let others: Vec<String> = chat
+            .participants
+            .iter()
+            .filter(|p| !own_handles.contains(p))
+            .cloned()
+            .collect();
+        if let Some(sender) = sender {
+            match session::lookup_keys(&session.client, sender, &others, &state.metrics).await {
+                Ok(found) => reachable = Some(found),
+                Err(e) => log::warn!("Participant lookup for chat {} failed: {}", id, e),
+            }
+        }
+    }
+
+    let participants = chat
+        .participants
+        .iter()
+        .map(|handle| {
+            let own = own_handles.contains(handle);
+            ChatParticipant {
+                handle: handle.clone(),
+                kind: if handle.starts_with("mailto:") {
+                    HandleKind::Email
+                } else {
+                    HandleKind::Phone
+                },
+                own,
+                imessage: if own {
+                    Some(true)
+                } else {
+                    reachable.as_ref().map(|r| r.contains(handle))
+                },
+                last_active: chat.last_active.get(handle).copied(),
+            }
+        })
+        .collect();
+    Ok(ApiJson(ParticipantsResponse {
+        chat_id: chat.id,
+        participants,
+    }))
+}
+
+pub async fn add_chat_labels(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    ApiJson(req): ApiJson<LabelsRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state
+        .chats
+        .update(&id, |chat| chat.labels.extend(req.labels))
+        .await?
+        .ok_or_else(|| chat_not_found(&id))?;
+    Ok(ApiJson(chat))
+}
+
+pub async fn remove_chat_label(
+    State(state): State<Arc<AppState>>,
+    Path((id, label)): Path<(String, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state
+        .chats
+        .update(&id, |chat| {
+            chat.labels.remove(&label);
+        })
+        .await?
+        .ok_or_else(|| chat_not_found(&id))?;
+    Ok(ApiJson(chat))
+}
+
+pub async fn set_chat_assignee(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    ApiJson(req): ApiJson<AssigneeRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state
+        .chats
+        .update(&id, |chat| chat.assignee = req.assignee)
+        .await?
+        .ok_or_else(|| chat_not_found(&id))?;
+    Ok(ApiJson(chat))
+}
+
+pub async fn add_chat_note(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    ApiJson(req): ApiJson<NoteRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let note = Note::new(req.text, req.author);
+    let added = note.clone();
+    state
+        .chats
+        .update(&id, |chat| chat.notes.push(added))
+        .await?
+        .ok_or_else(|| chat_not_found(&id))?;
+    Ok((StatusCode::CREATED, ApiJson(note)))
+}
+
+pub async fn delete_chat_note(
+    State(state): State<Arc<AppState>>,
+    Path((id, note_id)): Path<(String, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    let mut removed = false;
+    state
+        .chats
+        .update(&id, |chat| {
+            let before = chat.notes.len();
+            chat.notes.retain(|n| n.id != note_id);
+            removed = chat.notes.len() != before;
+        })
+        .await?
+        .ok_or_else(|| chat_not_found(&id))?;
+    if !removed {
+        return Err(note_not_found(&note_id));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_message_notes(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let notes = state.message_notes.get(&id).await;
+    Ok(ApiJson(NotesResponse { notes }))
+}
+
+pub async fn add_message_note(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    ApiJson(req): ApiJson<NoteRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let note = Note::new(req.text, req.author);
+    state.message_notes.add(&id, note.clone()).await?;
+    Ok((StatusCode::CREATED, ApiJson(note)))
+}
+
+pub async fn delete_message_note(
+    State(state): State<Arc<AppState>>,
+    Path((id, note_id)): Path<(String, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    if !state.message_notes.remove(&id, &note_id).await? {
+        return Err(note_not_found(&note_id));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn note_not_found(id: &str) -> AppError {
+    AppError::new(
+        StatusCode::NOT_FOUND,
+        anyhow::anyhow!("Note {} not found", id),
+    )
+}
+
+fn chat_not_found(id: &str) -> AppError {
+    AppError::new(
+        StatusCode::NOT_FOUND,
+        anyhow::anyhow!("Chat {} not found", id),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_phone_normalizes_us_numbers() {
+        for input in [
+            "5551234567",
+            "555-123-4567",
+            "(555) 123-4567",
+            "15551234567",
+            "+1 555 123 4567",
+            "tel:+15551234567",
+            " 5551234567 ",
+        ] {
+            assert_eq!(
+                format_phone(input).unwrap(),
+                "tel:+15551234567",
+                "{:?}",
+                input
+            );
+        }
+        for input in ["abc", "", "tel:"] {
+            assert!(format_phone(input).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn format_phone_keeps_international_numbers() {
+        assert_eq!(
+            format_phone("+44 20 7946 0958").unwrap(),
+            "tel:+442079460958"
+        );
+        assert_eq!(
+            format_phone("tel:+44 20 7946 0958").unwrap(),
+            "tel:+442079460958"
+        );
+        // A `+` means the country code is given, whatever the length
+        assert_eq!(format_phone("+4420794609").unwrap(), "tel:+4420794609");
+    }
+
+    #[test]
+    fn format_phone_handles_email() {
+        assert_eq!(
+            format_phone("you@icloud.com").unwrap(),
+            "mailto:you@icloud.com"
+        );
+        assert_eq!(
+            format_phone("mailto:you@icloud.com").unwrap(),
+            "mailto:you@icloud.com"
+        );
+        assert_eq!(
+            format_phone(" you@icloud.com ").unwrap(),
+            "mailto:you@icloud.com"
+        );
+    }
+
+    fn warnings(input: &str) -> Vec<String> {
+        normalize_warnings(input, &format_phone(input).unwrap())
+    }
+
+    #[test]
+    fn normalize_warnings_flags_assumptions() {
+        assert!(warnings("+15551234567").is_empty());
+        assert!(warnings("you@icloud.com").is_empty());
+        assert_eq!(
+            warnings("555-123-4567"),
+            ["No country code given; assumed US (+1)"]
+        );
+    }
+
+    #[test]
+    fn normalize_warnings_flags_invalid_handles() {
+        assert_eq!(
+            warnings("you@icloud"),
+            ["you@icloud does not look like an email address"]
+        );
+        assert_eq!(
+            warnings("1-800-FLOWERS"),
+            [
+                "Letters in the input were ignored",
+                "tel:+1800 is not a valid E.164 number (expected 8 to 15 digits)"
+            ]
+        );
+        assert_eq!(
+            warnings("+1234567890123456"),
+            ["tel:+1234567890123456 is not a valid E.164 number (expected 8 to 15 digits)"]
+        );
+    }
+
+    #[test]
+    fn parse_duration_accepts_units() {
+        assert_eq!(parse_duration("10s"), Some(Duration::from_secs(10)));
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration(" 7 "), Some(Duration::from_secs(7)));
+        assert_eq!(parse_duration("1h"), None);
+        assert_eq!(parse_duration("soon"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+}
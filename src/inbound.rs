@@ -0,0 +1,266 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustpush::{APSMessage, Message, MessageInst, MessageType, NormalMessage, PushError};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::handlers::AppState;
+use crate::pipeline::{Direction, PipelineMessage};
+use crate::send::{self, SendOptions, Target};
+use crate::session::Session;
+
+/// How many APS messages can wait for decoding before the reader applies
+/// backpressure.
+const INGEST_CAPACITY: usize = 1024;
+
+/// How long to wait for a delivery receipt to go out before moving on.
+const RECEIPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Background APS pump: decode incoming messages and run them through the
+/// inbound middleware pipeline. Draining also keeps the connection alive.
+///
+/// A separate reader moves APS messages off rustpush's broadcast channel
+/// into a bounded queue as soon as they arrive, so slow middleware or
+/// consumers can't make the broadcast lag and drop messages. It saves each
+/// notification to the inbox on the way, so one still waiting in the queue
+/// survives a crash. Decoded messages are acknowledged, deduplicated by GUID
+/// and kept in the inbox until they have been processed.
+///
+/// Each session gets its own pump, which stops once a reload retires it.
+/// Whatever the previous session or run left in the inbox is processed
+/// first.
+pub async fn pump(
+    state: Arc<AppState>,
+    session: Arc<Session>,
+    aps_receiver: broadcast::Receiver<APSMessage>,
+) {
+    let (tx, mut rx) = mpsc::channel(INGEST_CAPACITY);
+    tokio::spawn(read(state.clone(), session.clone(), aps_receiver, tx));
+
+    {
+        // Waits for the retired pump to finish the message it's on
+        let _processing = state.inbox.processing().await;
+        let pending = state.inbox.pending().await;
+        let received = state.inbox.received().await;
+        if !pending.is_empty() || !received.is_empty() {
+            log::info!(
+                "Resuming {} unprocessed and {} undecoded incoming messages",
+                pending.len(),
+                received.len()
+            );
+        }
+        for message in pending {
+            process(&state, &session, message).await;
+        }
+        for (key, msg) in received {
+            decode(&state, &session, msg, Some(key)).await;
+        }
+    }
+
+    loop {
+        let (msg, key) = tokio::select! {
+            biased;
+            _ = session.retired() => {
+                log::info!("Session replaced; stopping its APS pump");
+                break;
+            }
+            msg = rx.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+        };
+        let _processing = state.inbox.processing().await;
+        // The next session's pump resumes from the inbox once retired
+        if session.is_retired() {
+            break;
+        }
+        // Already decoded while resuming
+        if let Some(key) = key {
+            if !state.inbox.is_received(key).await {
+                continue;
+            }
+        }
+        decode(&state, &session, msg, key).await;
+    }
+}
+
+/// Decode one APS message and, if it is a message for us, acknowledge and
+/// ingest it. `key` is where the reader saved it in the inbox.
+async fn decode(state: &Arc<AppState>, session: &Session, msg: APSMessage, key: Option<u64>) {
+    let inst = match session.client.handle(msg).await {
+        Ok(Some(inst)) => inst,
+        result => {
+            if let Err(e) = result {
+                log::warn!("Failed to handle APS message: {}", e);
+            }
+            if let Some(key) = key {
+                if let Err(e) = state.inbox.discard(key).await {
+                    log::warn!("Failed to update the inbox: {}", e);
+                }
+            }
+            return;
+        }
+    };
+
+    if let Some(raw) = &state.raw_messages {
+        raw.record(&inst.id, format!("{:#?}", inst));
+    }
+
+    // Acknowledge duplicates too: Apple re-delivers until it gets the
+    // receipt
+    match tokio::time::timeout(RECEIPT_TIMEOUT, send_delivered(session, &inst)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => log::warn!("Failed to send delivery receipt for {}: {}", inst.id, e),
+        Err(_) => log::warn!("Timed out sending delivery receipt for {}", inst.id),
+    }
+
+    let message = PipelineMessage::from_inst(Direction::Inbound, &inst);
+    ingest(state, session, message, key).await;
+}
+
+/// Deduplicate a decoded incoming message, save it to the inbox and process
+/// it. `received` is the inbox key of the notification it was decoded from,
+/// if any. Returns `false` if it was a duplicate and dropped.
+pub async fn ingest(
+    state: &Arc<AppState>,
+    session: &Session,
+    message: PipelineMessage,
+    received: Option<u64>,
+) -> bool {
+    match state.inbox.push(message.clone(), received).await {
+        Ok(false) => {
+            log::debug!("Dropping duplicate delivery of {}", message.id);
+            return false;
+        }
+        Ok(true) => {}
+        Err(e) => log::error!("Failed to save incoming message {}: {}", message.id, e),
+    }
+    state.metrics.record_receive();
+    process(state, session, message).await;
+    true
+}
+
+/// Forward APS messages into the ingest queue, saving notifications to the
+/// inbox first. Does nothing else, so this keeps up with the broadcast
+/// channel however slow processing gets.
+async fn read(
+    state: Arc<AppState>,
+    session: Arc<Session>,
+    mut aps_receiver: broadcast::Receiver<APSMessage>,
+    tx: mpsc::Sender<(APSMessage, Option<u64>)>,
+) {
+    loop {
+        let received = tokio::select! {
+            biased;
+            _ = session.retired() => break,
+            received = aps_receiver.recv() => received,
+        };
+        match received {
+            Ok(msg) => {
+                // Only notifications can carry messages; the rest is
+                // connection housekeeping not worth a write
+                let key = match msg {
+                    APSMessage::Notification { .. } => match state.inbox.receive(&msg).await {
+                        Ok(key) => Some(key),
+                        Err(e) => {
+                            log::error!("Failed to save incoming notification: {}", e);
+                            None
+                        }
+                    },
+                    _ => None,
+                };
+                if tx.send((msg, key)).await.is_err() {
+                    break;
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                log::error!("APS reader lagged; {} messages were dropped", n);
+            }
+            Err(broadcast::error::RecvError::Closed) => {
+                log::error!("APS channel closed");
+                break;
+            }
+        }
+    }
+}
+
+/// Send the delivery receipt the sender's devices asked for, from whichever
+/// of our handles the message was addressed to.
+async fn send_delivered(session: &Session, inst: &MessageInst) -> Result<(), PushError> {
+    if !inst.send_delivered {
+        return Ok(());
+    }
+    let Some(conversation) = inst.conversation.clone() else {
+        return Ok(());
+    };
+    let own_handles = session.client.identity.get_handles().await;
+    let Some(handle) = conversation
+        .participants
+        .iter()
+        .find(|p| own_handles.contains(p))
+        .cloned()
+    else {
+        return Ok(());
+    };
+    let mut receipt = MessageInst::new(conversation, &handle, Message::Delivered);
+    receipt.id = inst.id.clone();
+    receipt.target = inst.target.clone();
+    session.client.send(&mut receipt).await?;
+    Ok(())
+}
+
+/// Run one decoded message through the pipeline and its consumers, then
+/// drop it from the inbox.
+async fn process(state: &Arc<AppState>, session: &Session, message: PipelineMessage) {
+    let id = message.id.clone();
+    handle_message(state, session, message).await;
+    if let Err(e) = state.inbox.remove(&id).await {
+        log::warn!(
+            "Failed to remove incoming message {} from the inbox: {}",
+            id,
+            e
+        );
+    }
+}
+
+async fn handle_message(state: &Arc<AppState>, session: &Session, mut message: PipelineMessage) {
+    let own_handles = session.client.identity.get_handles().await.to_vec();
+    message.from_me = message
+        .sender
+        .as_ref()
+        .is_some_and(|s| own_handles.contains(s));
+    message.chat_id = Some(state.chats.id_for(&message).await);
+
+    let Ok(replies) = state.pipeline.run(&mut message).await else {
+        return;
+    };
+    log::debug!("Inbound message {} accepted", message.id);
+
+    if let Err(e) = state.chats.touch(&message).await {
+        log::warn!("Failed to record chat activity: {}", e);
+    }
+
+    if !replies.is_empty() {
+        for reply in replies {
+            let state = state.clone();
+            let target = Target::reply_to(&message, &own_handles);
+            tokio::spawn(async move {
+                let normal = NormalMessage::new(reply, MessageType::IMessage);
+                if let Err(e) = send::send(
+                    &state,
+                    target,
+                    Message::Message(normal),
+                    SendOptions::default(),
+                )
+                .await
+                {
+                    log::warn!("Failed to send reply: {}", e);
+                }
+            });
+        }
+    }
+
+    if let Some(chatbot) = &state.chatbot {
+        chatbot.dispatch(state.clone(), message);
+    }
+}
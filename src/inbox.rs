@@ -0,0 +1,232 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use rustpush::APSMessage;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::persist::{self, JsonLog};
+use crate::pipeline::PipelineMessage;
+
+/// How many incoming message GUIDs to remember for deduplication.
+const SEEN_CAPACITY: usize = 10_000;
+
+/// Lines the received log may hold before it is compacted down to the
+/// notifications that haven't been decoded yet.
+const RECEIVED_COMPACT_LINES: usize = 1_000;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ReceivedEntry {
+    /// An APS notification as it was read.
+    Received { key: u64, message: APSMessage },
+    /// The notification with this key has been decoded.
+    Done(u64),
+}
+
+struct Received {
+    log: JsonLog,
+    /// Notifications not yet decoded, by read order.
+    messages: BTreeMap<u64, APSMessage>,
+    next_key: u64,
+}
+
+struct Seen {
+    log: JsonLog,
+    /// GUIDs of recently admitted messages, oldest first.
+    ids: VecDeque<String>,
+    set: HashSet<String>,
+}
+
+/// Incoming messages that haven't been through the inbound pipeline yet.
+///
+/// APS notifications are appended to `<data_dir>/inbox_received.jsonl` as
+/// they are read, before decoding. That log has its own lock and is only
+/// appended to, and compacted once most of it has been decoded, so saving a
+/// notification stays cheap however far behind processing is. Decoded messages wait in
+/// `<data_dir>/inbox.json` until they have been processed, so nothing that
+/// reached the server is lost to a crash or restart. GUIDs of admitted
+/// messages are appended to `<data_dir>/inbox_seen.jsonl` so a message Apple
+/// delivers more than once is only processed the first time.
+pub struct Inbox {
+    path: PathBuf,
+    pending: Mutex<VecDeque<PipelineMessage>>,
+    received: Mutex<Received>,
+    seen: Mutex<Seen>,
+    /// Held while a message is being processed, so a new session's pump
+    /// can't pick up a message the retired one is still working on.
+    processing: Mutex<()>,
+}
+
+impl Inbox {
+    pub fn load(data_dir: &str) -> anyhow::Result<Self> {
+        let dir = PathBuf::from_str(data_dir).unwrap();
+        let path = dir.join("inbox.json");
+        let pending = persist::load_json(&path)?;
+
+        let (log, entries) = JsonLog::load(dir.join("inbox_received.jsonl"))?;
+        let mut messages = BTreeMap::new();
+        let mut next_key = 0;
+        for entry in entries {
+            match entry {
+                ReceivedEntry::Received { key, message } => {
+                    next_key = next_key.max(key + 1);
+                    messages.insert(key, message);
+                }
+                ReceivedEntry::Done(key) => {
+                    messages.remove(&key);
+                }
+            }
+        }
+
+        let (seen_log, ids) = JsonLog::load::<String>(dir.join("inbox_seen.jsonl"))?;
+        let mut seen = Seen {
+            log: seen_log,
+            ids: VecDeque::new(),
+            set: HashSet::new(),
+        };
+        for id in ids {
+            seen.remember(id);
+        }
+
+        Ok(Self {
+            path,
+            pending: Mutex::new(pending),
+            received: Mutex::new(Received {
+                log,
+                messages,
+                next_key,
+            }),
+            seen: Mutex::new(seen),
+            processing: Mutex::new(()),
+        })
+    }
+
+    /// Save an APS notification as it is read. Returns the key to hand to
+    /// `push` or `discard` once it has been decoded.
+    pub async fn receive(&self, message: &APSMessage) -> anyhow::Result<u64> {
+        let mut received = self.received.lock().await;
+        let key = received.next_key;
+        received
+            .log
+            .append(&ReceivedEntry::Received {
+                key,
+                message: message.clone(),
+            })
+            .await?;
+        received.next_key += 1;
+        received.messages.insert(key, message.clone());
+        Ok(key)
+    }
+
+    /// Notifications saved by `receive` that were never decoded, in the
+    /// order they were read.
+    pub async fn received(&self) -> Vec<(u64, APSMessage)> {
+        let received = self.received.lock().await;
+        received
+            .messages
+            .iter()
+            .map(|(key, message)| (*key, message.clone()))
+            .collect()
+    }
+
+    /// Whether the notification saved under `key` is still waiting to be
+    /// decoded.
+    pub async fn is_received(&self, key: u64) -> bool {
+        self.received.lock().await.messages.contains_key(&key)
+    }
+
+    /// Drop a saved notification that didn't decode to a message.
+    pub async fn discard(&self, key: u64) -> anyhow::Result<()> {
+        let mut received = self.received.lock().await;
+        if received.messages.remove(&key).is_none() {
+            return Ok(());
+        }
+        received.log.append(&ReceivedEntry::Done(key)).await?;
+        if received.log.lines() >= RECEIVED_COMPACT_LINES.max(2 * received.messages.len()) {
+            let Received { log, messages, .. } = &mut *received;
+            let live: Vec<ReceivedEntry> = messages
+                .iter()
+                .map(|(key, message)| ReceivedEntry::Received {
+                    key: *key,
+                    message: message.clone(),
+                })
+                .collect();
+            log.rewrite(&live).await?;
+        }
+        Ok(())
+    }
+
+    /// Queue a decoded message for processing, then drop the notification
+    /// it was decoded from. Returns `false` without queueing it if a message
+    /// with the same GUID was already admitted.
+    pub async fn push(
+        &self,
+        message: PipelineMessage,
+        received: Option<u64>,
+    ) -> anyhow::Result<bool> {
+        let admitted = self.seen.lock().await.admit(&message.id).await?;
+        if admitted {
+            let mut pending = self.pending.lock().await;
+            pending.push_back(message);
+            self.save(&pending).await?;
+        }
+        // A crash before this re-decodes the notification on restart, and
+        // the seen log then drops it as a duplicate
+        if let Some(key) = received {
+            self.discard(key).await?;
+        }
+        Ok(admitted)
+    }
+
+    /// Messages left over from before a restart or reload, oldest first.
+    pub async fn pending(&self) -> Vec<PipelineMessage> {
+        self.pending.lock().await.iter().cloned().collect()
+    }
+
+    /// Mark a message as processed.
+    pub async fn remove(&self, id: &str) -> anyhow::Result<()> {
+        let mut pending = self.pending.lock().await;
+        pending.retain(|m| m.id != id);
+        self.save(&pending).await
+    }
+
+    /// Hold while processing messages from the inbox.
+    pub async fn processing(&self) -> MutexGuard<'_, ()> {
+        self.processing.lock().await
+    }
+
+    async fn save(&self, pending: &VecDeque<PipelineMessage>) -> anyhow::Result<()> {
+        persist::write_atomic(&self.path, &serde_json::to_vec(pending)?).await
+    }
+}
+
+impl Seen {
+    fn remember(&mut self, id: String) -> bool {
+        if !self.set.insert(id.clone()) {
+            return false;
+        }
+        self.ids.push_back(id);
+        if self.ids.len() > SEEN_CAPACITY {
+            if let Some(oldest) = self.ids.pop_front() {
+                self.set.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Record `id` as admitted. Returns `false` if it already was.
+    async fn admit(&mut self, id: &str) -> anyhow::Result<bool> {
+        if !self.remember(id.to_string()) {
+            return Ok(false);
+        }
+        if self.log.lines() + 1 >= 2 * SEEN_CAPACITY {
+            let Seen { log, ids, .. } = self;
+            log.rewrite(ids.iter()).await?;
+        } else {
+            self.log.append(&id).await?;
+        }
+        Ok(true)
+    }
+}
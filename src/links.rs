@@ -0,0 +1,170 @@
+use async_trait::async_trait;
+use regex::Regex;
+
+use crate::pipeline::{Action, Direction, MessageMiddleware, PipelineMessage};
+
+/// Annotates incoming messages that share an iCloud link (shared photos and
+/// albums, iCloud Drive files, iWork documents, notes) with what the link
+/// points to, so consumers get `icloud_link`, `icloud_link_type` and, when
+/// the link carries one, `icloud_link_title` instead of a bare URL. Only the
+/// first link in a message is described.
+pub struct ICloudLinks {
+    pattern: Regex,
+}
+
+impl Default for ICloudLinks {
+    fn default() -> Self {
+        Self {
+            pattern: Regex::new(r"https://(?:www\.)?icloud\.com/([A-Za-z-]+)/?[^\s]*").unwrap(),
+        }
+    }
+}
+
+#[async_trait]
+impl MessageMiddleware for ICloudLinks {
+    fn name(&self) -> &str {
+        "icloud-links"
+    }
+
+    async fn process(&self, message: &mut PipelineMessage) -> Action {
+        if message.direction != Direction::Inbound {
+            return Action::Continue;
+        }
+        let Some(link) = message.text.as_deref().and_then(|text| self.find(text)) else {
+            return Action::Continue;
+        };
+
+        let annotations = &mut message.annotations;
+        annotations.insert("icloud_link".to_string(), link.url);
+        annotations.insert("icloud_link_type".to_string(), link.kind.to_string());
+        if let Some(title) = link.title {
+            annotations.insert("icloud_link_title".to_string(), title);
+        }
+        Action::Continue
+    }
+}
+
+/// The first iCloud link in a message and what it points to.
+#[derive(Debug, PartialEq)]
+struct Link {
+    url: String,
+    kind: &'static str,
+    title: Option<String>,
+}
+
+impl ICloudLinks {
+    fn find(&self, text: &str) -> Option<Link> {
+        let captures = self.pattern.captures(text)?;
+        // Punctuation right after a link belongs to the sentence around it
+        let url = captures[0]
+            .trim_end_matches(['.', ',', ';', ':', '!', '?', ')'])
+            .to_string();
+        let kind = match &captures[1] {
+            "photos" => "photos",
+            "sharedalbum" => "shared_album",
+            "iclouddrive" => "file",
+            "keynote" | "keynote-live" | "pages" | "numbers" => "document",
+            "notes" => "note",
+            "reminders" => "reminders",
+            "attachment" => "mail_drop",
+            _ => "other",
+        };
+        // Drive, iWork and Notes links end in `#Title_With_Underscores`;
+        // for photos the fragment is an access token, not a name
+        let title = match kind {
+            "file" | "document" | "note" => url
+                .split_once('#')
+                .map(|(_, fragment)| percent_decode(fragment).replace('_', " "))
+                .filter(|t| !t.is_empty()),
+            _ => None,
+        };
+        Some(Link { url, kind, title })
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok());
+        match hex
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|h| u8::from_str_radix(h, 16).ok())
+        {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_decode_decodes_escapes() {
+        assert_eq!(percent_decode("Q3%20Plan"), "Q3 Plan");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+        assert_eq!(percent_decode("100%25"), "100%");
+    }
+
+    #[test]
+    fn percent_decode_keeps_invalid_escapes() {
+        assert_eq!(percent_decode("50%off"), "50%off");
+        assert_eq!(percent_decode("trailing%2"), "trailing%2");
+        assert_eq!(percent_decode("%"), "%");
+    }
+
+    fn find(text: &str) -> Option<Link> {
+        ICloudLinks::default().find(text)
+    }
+
+    #[test]
+    fn find_classifies_links() {
+        for (url, kind) in [
+            ("https://www.icloud.com/photos/#0aBcD", "photos"),
+            ("https://www.icloud.com/sharedalbum/#B0aBcD", "shared_album"),
+            ("https://www.icloud.com/iclouddrive/0aBcD#Budget", "file"),
+            ("https://www.icloud.com/keynote/0aBcD#Deck", "document"),
+            ("https://www.icloud.com/pages/0aBcD#Letter", "document"),
+            ("https://www.icloud.com/notes/0aBcD#Groceries", "note"),
+            ("https://www.icloud.com/reminders/0aBcD", "reminders"),
+            ("https://www.icloud.com/attachment/?u=abc", "mail_drop"),
+            ("https://icloud.com/mail", "other"),
+        ] {
+            let link = find(&format!("look: {} thanks", url)).unwrap();
+            assert_eq!(link.url, url);
+            assert_eq!(link.kind, kind, "{}", url);
+        }
+        assert_eq!(find("https://example.com/photos/1"), None);
+    }
+
+    #[test]
+    fn find_takes_titles_only_from_named_links() {
+        let link = find("https://www.icloud.com/iclouddrive/0aBcD#Q3_Plan%202").unwrap();
+        assert_eq!(link.title.as_deref(), Some("Q3 Plan 2"));
+        let link = find("https://www.icloud.com/photos/#0aBcD").unwrap();
+        assert_eq!(link.title, None);
+        let link = find("https://www.icloud.com/notes/0aBcD#").unwrap();
+        assert_eq!(link.title, None);
+    }
+
+    #[test]
+    fn find_drops_trailing_punctuation() {
+        let link = find("See https://www.icloud.com/notes/0aBcD#Groceries.").unwrap();
+        assert_eq!(link.url, "https://www.icloud.com/notes/0aBcD#Groceries");
+        assert_eq!(link.title.as_deref(), Some("Groceries"));
+        let link = find("(https://www.icloud.com/photos/#0aBcD), ok?").unwrap();
+        assert_eq!(link.url, "https://www.icloud.com/photos/#0aBcD");
+    }
+}
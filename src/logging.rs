@@ -0,0 +1,139 @@
+use std::sync::{Arc, RwLock};
+
+use env_logger::filter::{Builder, Filter};
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// The active log filter, in `RUST_LOG` syntax (e.g. `info,rustpush=debug`).
+/// Can be changed at runtime through `/api/admin/loglevel`.
+pub struct LogFilter {
+    state: RwLock<(String, Filter)>,
+}
+
+impl LogFilter {
+    pub fn spec(&self) -> String {
+        self.state.read().unwrap().0.clone()
+    }
+
+    pub fn set(&self, spec: &str) -> anyhow::Result<()> {
+        validate(spec)?;
+        let filter = Builder::new().parse(spec).build();
+        log::set_max_level(filter.filter());
+        *self.state.write().unwrap() = (spec.to_string(), filter);
+        log::info!("Log filter set to {:?}", spec);
+        Ok(())
+    }
+
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.state.read().unwrap().1.enabled(metadata)
+    }
+}
+
+struct RuntimeLogger {
+    inner: env_logger::Logger,
+    filter: Arc<LogFilter>,
+}
+
+impl Log for RuntimeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Install the pretty_env_logger format with a filter that starts from
+/// `RUST_LOG` and can be replaced later.
+pub fn init() -> Arc<LogFilter> {
+    let spec = std::env::var("RUST_LOG").unwrap_or_default();
+    let filter = Arc::new(LogFilter {
+        state: RwLock::new((spec.clone(), Builder::new().parse(&spec).build())),
+    });
+
+    let inner = pretty_env_logger::formatted_timed_builder()
+        .filter_level(LevelFilter::Trace)
+        .build();
+    let max_level = filter.state.read().unwrap().1.filter();
+    log::set_boxed_logger(Box::new(RuntimeLogger {
+        inner,
+        filter: filter.clone(),
+    }))
+    .expect("logger already initialized");
+    log::set_max_level(max_level);
+    filter
+}
+
+/// Crates whose logs are worth filtering on their own. A bare directive
+/// must name one of these, a module path or a level.
+const KNOWN_TARGETS: &[&str] = &[
+    "imessage_api",
+    "rustpush",
+    "keystore",
+    "axum",
+    "hyper",
+    "reqwest",
+    "rustls",
+    "tokio",
+    "tower_http",
+    "wasmtime",
+];
+
+/// env_logger silently skips directives it can't parse, and takes a bare
+/// word it doesn't know as a module name that matches nothing; reject both
+/// so a typo doesn't quietly turn logging off.
+fn validate(spec: &str) -> anyhow::Result<()> {
+    let directives = spec.split('/').next().unwrap_or_default();
+    for directive in directives
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+    {
+        let Some((_, level)) = directive.split_once('=') else {
+            if directive.parse::<LevelFilter>().is_err()
+                && !directive.contains("::")
+                && !KNOWN_TARGETS.contains(&directive)
+            {
+                anyhow::bail!("Unknown log level or module {:?}", directive);
+            }
+            continue;
+        };
+        if level.parse::<LevelFilter>().is_err() {
+            anyhow::bail!("Invalid log level {:?} in {:?}", level, directive);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_accepts_env_logger_specs() {
+        for spec in [
+            "",
+            "info",
+            "info,rustpush=debug",
+            "rustpush",
+            "warn/send",
+            "imessage_api::send",
+        ] {
+            assert!(validate(spec).is_ok(), "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn validate_rejects_unknown_levels() {
+        assert!(validate("rustpush=verbose").is_err());
+        assert!(validate("info, hyper=loud").is_err());
+        assert!(validate("debgu").is_err());
+        assert!(validate("inf,rustpush=debug").is_err());
+    }
+}
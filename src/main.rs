@@ -1,30 +1,71 @@
+mod auth;
+mod autoresponder;
+mod backoff;
+mod bench;
+mod broadcast;
+mod chatbot;
+mod chats;
+mod check;
+mod debug;
+mod envelope;
 mod error;
 mod handlers;
+mod inbound;
+mod inbox;
+mod links;
+mod logging;
+mod messages;
+mod metrics;
+mod notes;
+mod outbox;
+mod persist;
+mod pipeline;
+mod send;
 mod session;
+mod topics;
 mod types;
+#[cfg(feature = "wasm")]
+mod wasm;
 
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 
-use axum::extract::Request;
+use axum::extract::{MatchedPath, Request, State};
 use axum::http::StatusCode;
 use axum::middleware::{self, Next};
-use axum::response::IntoResponse;
-use axum::routing::{get, post};
+use axum::response::Response;
+use axum::routing::{delete, get, post, put};
 use axum::Router;
 use log::info;
+use tokio::sync::{Mutex, Notify};
 use tower_http::cors::CorsLayer;
 
+use auth::ApiKeys;
+use autoresponder::AutoResponder;
+use backoff::Backoff;
+use broadcast::Broadcasts;
+use chatbot::Chatbot;
+use chats::Chats;
+use debug::RawMessages;
 use handlers::AppState;
+use inbox::Inbox;
+use links::ICloudLinks;
+use messages::Messages;
+use metrics::Metrics;
+use notes::MessageNotes;
+use outbox::Outbox;
+use persist::Writer;
+use pipeline::{MessageLogger, Pipeline};
+use topics::Topics;
 
 async fn auth_middleware(
+    State(api_keys): State<Arc<ApiKeys>>,
     req: Request,
     next: Next,
-) -> Result<impl IntoResponse, StatusCode> {
-    let api_key = std::env::var("IMESSAGE_API_KEY").unwrap_or_default();
-
-    if api_key.is_empty() {
-        return Ok(next.run(req).await);
-    }
+) -> Response {
+    let admin_key = std::env::var("IMESSAGE_ADMIN_KEY").unwrap_or_default();
 
     let auth_header = req
         .headers()
@@ -34,62 +75,369 @@ async fn auth_middleware(
 
     let token = auth_header.strip_prefix("Bearer ").unwrap_or(auth_header);
 
-    if token == api_key {
-        Ok(next.run(req).await)
+    // Admin endpoints can shut the server down and replace session files,
+    // so they only ever take the separate admin key and are off without one
+    let path = req.uri().path();
+    let is_admin = path.starts_with("/api/admin/") || path.starts_with("/v1/admin/");
+    let allowed = if is_admin {
+        if admin_key.is_empty() {
+            return envelope::status(StatusCode::FORBIDDEN);
+        }
+        token == admin_key
+    } else {
+        !api_keys.required() || api_keys.accepts(token)
+    };
+
+    if allowed {
+        next.run(req).await
     } else {
-        Err(StatusCode::UNAUTHORIZED)
+        envelope::status(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// Resolves on SIGINT, SIGTERM or `POST /api/admin/shutdown`.
+async fn shutdown_signal(state: Arc<AppState>) {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                log::warn!("Can't listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+        _ = terminate => info!("Received SIGTERM, shutting down"),
+        _ = state.shutdown.notified() => {}
     }
 }
 
+async fn latency_middleware(
+    State(state): State<Arc<AppState>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let endpoint = match req.extensions().get::<MatchedPath>() {
+        Some(path) => format!("{} {}", req.method(), path.as_str()),
+        None => format!("{} (unmatched)", req.method()),
+    };
+    let started = Instant::now();
+    let response = next.run(req).await;
+    state.metrics.record_request(endpoint, started.elapsed());
+    response
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    pretty_env_logger::init_timed();
+    let log_filter = logging::init();
+
+    if std::env::args().nth(1).as_deref() == Some("bench") {
+        let args: Vec<String> = std::env::args().skip(2).collect();
+        return bench::run(&args).await;
+    }
+
+    let data_dir = match std::env::var("IMESSAGE_DATA_DIR") {
+        Ok(dir) => dir,
+        Err(_) => session::default_data_dir()?,
+    };
+
+    if std::env::args().nth(1).as_deref() == Some("check") {
+        let passed = check::run(&data_dir);
+        std::process::exit(if passed { 0 } else { 1 });
+    }
 
-    let default_data_dir =
-        "/home/opc/.var/app/app.openbubbles.OpenBubbles/data/bluebubbles".to_string();
-    let data_dir = std::env::var("IMESSAGE_DATA_DIR").unwrap_or(default_data_dir);
     let port: u16 = std::env::var("IMESSAGE_API_PORT")
         .ok()
         .and_then(|p| p.parse().ok())
         .unwrap_or(8787);
+    let broadcast_concurrency: usize = std::env::var("IMESSAGE_BROADCAST_CONCURRENCY")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(5);
+    let broadcast_max_concurrency: usize = std::env::var("IMESSAGE_BROADCAST_MAX_CONCURRENCY")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(20);
+    let broadcast_interval_ms: u64 = std::env::var("IMESSAGE_BROADCAST_INTERVAL_MS")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(200);
+    let autoresponder_cooldown_secs: u64 = std::env::var("IMESSAGE_AUTORESPONDER_COOLDOWN_SECS")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(60);
+    let outbox_capacity: usize = std::env::var("IMESSAGE_OUTBOX_MAX")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(1000);
+    let outbox_ttl_secs: u64 = std::env::var("IMESSAGE_OUTBOX_TTL_SECS")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(3600);
+    let slow_send_ms: u64 = std::env::var("IMESSAGE_SLOW_SEND_MS")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(2000);
+    let backoff_base_secs: u64 = std::env::var("IMESSAGE_BACKOFF_BASE_SECS")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(5);
+    let backoff_max_secs: u64 = std::env::var("IMESSAGE_BACKOFF_MAX_SECS")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(300);
+    let chatbot_url = std::env::var("IMESSAGE_CHATBOT_URL").ok();
+    let chatbot_context: usize = std::env::var("IMESSAGE_CHATBOT_CONTEXT")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(10);
+    let chatbot_timeout_secs: u64 = std::env::var("IMESSAGE_CHATBOT_TIMEOUT_SECS")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(30);
+    let warmup_handles: Vec<String> = std::env::var("IMESSAGE_WARMUP_HANDLES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|h| !h.is_empty())
+        .filter_map(|h| {
+            handlers::format_phone(h)
+                .inspect_err(|e| log::warn!("Ignoring IMESSAGE_WARMUP_HANDLES entry: {}", e))
+                .ok()
+        })
+        .collect();
+    let allowed_recipients: Option<HashSet<String>> = std::env::var("IMESSAGE_ALLOWED_RECIPIENTS")
+        .ok()
+        .map(|list| {
+            list.split(',')
+                .map(str::trim)
+                .filter(|h| !h.is_empty())
+                .filter_map(|h| {
+                    handlers::format_phone(h)
+                        .inspect_err(|e| {
+                            log::warn!("Ignoring IMESSAGE_ALLOWED_RECIPIENTS entry: {}", e)
+                        })
+                        .ok()
+                })
+                .collect()
+        });
+
+    let debug = std::env::var("IMESSAGE_DEBUG").is_ok_and(|v| v == "1" || v == "true");
+    let optional_topics: Vec<String> = std::env::var("IMESSAGE_OPTIONAL_TOPICS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    let api_keys = Arc::new(ApiKeys::load(
+        std::env::var("IMESSAGE_API_KEY").ok(),
+        std::env::var("IMESSAGE_API_KEYS_FILE")
+            .ok()
+            .map(PathBuf::from),
+    )?);
+    {
+        let api_keys = api_keys.clone();
+        tokio::spawn(async move { api_keys.watch().await });
+    }
 
     info!("Data dir: {}", data_dir);
-    info!("Restoring session...");
+    if std::env::var("IMESSAGE_ADMIN_KEY").unwrap_or_default().is_empty() {
+        info!("IMESSAGE_ADMIN_KEY is not set; admin endpoints are disabled");
+    }
+    if debug {
+        log::warn!("Debug mode: recording raw incoming messages in memory");
+    }
+    if let Some(allowed) = &allowed_recipients {
+        info!("Sandbox mode: sends limited to {} handles", allowed.len());
+    }
 
-    let (client, _conn, mut aps_receiver) = session::restore(&data_dir).await?;
+    let metrics = Arc::new(Metrics::new(Duration::from_millis(slow_send_ms)));
 
-    // Background APS pump: drain incoming messages to keep the connection alive
+    let started_at = Instant::now();
+    let broadcasts = Broadcasts::load(
+        &data_dir,
+        broadcast_concurrency,
+        broadcast_max_concurrency,
+        broadcast_interval_ms,
+    )?;
+
+    let autoresponder =
+        AutoResponder::load(&data_dir, Duration::from_secs(autoresponder_cooldown_secs))?;
+
+    let mut pipeline = Pipeline::default();
+    pipeline.register(MessageLogger);
+    pipeline.register(ICloudLinks::default());
+    #[cfg(feature = "wasm")]
+    if let Ok(dir) = std::env::var("IMESSAGE_WASM_PLUGINS") {
+        for plugin in wasm::load_plugins(&dir)? {
+            pipeline.register(plugin);
+        }
+    }
+    pipeline.register(autoresponder.clone());
+
+    let state = Arc::new(AppState {
+        session: RwLock::new(None),
+        restore_error: RwLock::new(None),
+        reloading: Mutex::new(()),
+        shutdown: Notify::new(),
+        data_dir: data_dir.clone(),
+        allowed_recipients,
+        log_filter,
+        started_at,
+        metrics,
+        backoff: Backoff::new(
+            Duration::from_secs(backoff_base_secs),
+            Duration::from_secs(backoff_max_secs),
+        ),
+        broadcasts,
+        pipeline,
+        autoresponder,
+        chatbot: chatbot_url.map(|url| {
+            Arc::new(Chatbot::new(
+                url,
+                chatbot_context,
+                Duration::from_secs(chatbot_timeout_secs),
+            ))
+        }),
+        chats: Chats::load(&data_dir)?,
+        message_notes: MessageNotes::load(&data_dir)?,
+        inbox: Inbox::load(&data_dir)?,
+        messages: Messages::load(&data_dir)?,
+        outbox: Outbox::load(
+            &data_dir,
+            outbox_capacity,
+            Duration::from_secs(outbox_ttl_secs),
+        )?,
+        topics: Topics::load(&data_dir, optional_topics)?,
+        writer: Writer::spawn(),
+        raw_messages: debug.then(RawMessages::default),
+    });
+
+    // Restore in the background so the listener is up right away; /readyz
+    // reports when the session is live. Keep serving without a session if it
+    // fails so it can be inspected and repaired through the API
+    let restoring = state.clone();
     tokio::spawn(async move {
-        loop {
-            match aps_receiver.recv().await {
-                Ok(_msg) => {
-                    log::debug!("APS message received (draining)");
-                }
-                Err(tokio::sync::broadcast::error::RecvError::Lagged(n)) => {
-                    log::warn!("APS receiver lagged by {} messages", n);
-                }
-                Err(tokio::sync::broadcast::error::RecvError::Closed) => {
-                    log::error!("APS channel closed");
-                    break;
+        info!("Restoring session...");
+        match session::activate(&restoring).await {
+            Ok(active) => {
+                info!("Session ready");
+                if !warmup_handles.is_empty() {
+                    session::warm_up(&active.client, &warmup_handles, &restoring.metrics).await;
                 }
             }
+            Err(e) => log::error!(
+                "Session restore failed, serving the API without a session: {:#}",
+                e
+            ),
         }
     });
 
-    let state = Arc::new(AppState { client });
+    tokio::spawn(outbox::run(state.clone()));
+    tokio::spawn(chats::run(state.clone()));
+
+    // Served twice: bare responses under /api, enveloped under /v1
+    let api = Router::new()
+        .route("/send", post(handlers::send_message))
+        .route("/send/individual", post(handlers::send_individual))
+        .route("/broadcast", post(handlers::create_broadcast))
+        .route("/broadcast/:id", get(handlers::get_broadcast))
+        .route("/broadcast/:id/events", get(handlers::broadcast_events))
+        .route(
+            "/autoresponder/rules",
+            get(handlers::list_rules).post(handlers::create_rule),
+        )
+        .route("/autoresponder/rules/:id", delete(handlers::delete_rule))
+        .route(
+            "/autoresponder/chats/:id",
+            put(handlers::set_chat_autoresponder),
+        )
+        .route("/chats", get(handlers::list_chats))
+        .route(
+            "/chats/:id/participants",
+            get(handlers::get_chat_participants),
+        )
+        .route("/chats/:id/labels", post(handlers::add_chat_labels))
+        .route(
+            "/chats/:id/labels/:label",
+            delete(handlers::remove_chat_label),
+        )
+        .route("/chats/:id/assignee", post(handlers::set_chat_assignee))
+        .route("/chats/:id/notes", post(handlers::add_chat_note))
+        .route(
+            "/chats/:id/notes/:note_id",
+            delete(handlers::delete_chat_note),
+        )
+        .route(
+            "/messages/:id/notes",
+            get(handlers::get_message_notes).post(handlers::add_message_note),
+        )
+        .route(
+            "/messages/:id/notes/:note_id",
+            delete(handlers::delete_message_note),
+        )
+        .route("/normalize", get(handlers::normalize))
+        .route("/handles", get(handlers::get_handles))
+        .route("/health", get(handlers::health))
+        .route("/status", get(handlers::status))
+        .route("/metrics", get(handlers::metrics))
+        .route("/debug/inject", post(handlers::inject_message))
+        .route("/admin/slow-sends", get(handlers::slow_sends))
+        .route(
+            "/admin/loglevel",
+            get(handlers::get_log_level).put(handlers::set_log_level),
+        )
+        .route("/admin/reload-session", post(handlers::reload_session))
+        .route(
+            "/admin/topics",
+            get(handlers::get_topics).put(handlers::set_topics),
+        )
+        .route("/admin/debug/messages/:id", get(handlers::get_raw_message))
+        .route("/admin/session/files", get(handlers::list_session_files))
+        .route(
+            "/admin/session/files/:name",
+            put(handlers::upload_session_file),
+        )
+        .route("/admin/shutdown", post(handlers::shutdown))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            latency_middleware,
+        ));
 
     let app = Router::new()
-        .route("/api/send", post(handlers::send_message))
-        .route("/api/handles", get(handlers::get_handles))
-        .route("/api/health", get(handlers::health))
-        .layer(middleware::from_fn(auth_middleware))
+        .nest("/api", api.clone())
+        .nest("/v1", api)
+        .layer(middleware::from_fn_with_state(api_keys, auth_middleware))
+        // Probes don't carry API keys
+        .route("/readyz", get(handlers::readyz))
+        .fallback(envelope::not_found)
+        .layer(middleware::from_fn(envelope::middleware))
         .layer(CorsLayer::permissive())
-        .with_state(state);
+        .with_state(state.clone());
 
     let addr = format!("0.0.0.0:{}", port);
     info!("Starting server on {}", addr);
     let listener = tokio::net::TcpListener::bind(&addr).await?;
-    axum::serve(listener, app).await?;
+    let writer = state.writer.clone();
+    let stopped = state.clone();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal(state))
+        .await?;
+    writer.flush().await;
+    if let Err(e) = stopped.chats.flush().await {
+        log::error!("Failed to save chats: {}", e);
+    }
+    info!("Server stopped");
 
     Ok(())
 }
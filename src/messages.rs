@@ -0,0 +1,105 @@
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::chats::now_secs;
+use crate::persist::JsonLog;
+
+/// How many sent messages to remember. Client-supplied IDs are checked for
+/// uniqueness against this window.
+const CAPACITY: usize = 10_000;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SentMessage {
+    pub id: String,
+    pub participants: Vec<String>,
+    pub sent_at: u64,
+}
+
+struct Inner {
+    log: JsonLog,
+    sent: VecDeque<SentMessage>,
+    ids: HashSet<String>,
+    /// IDs claimed by requests that haven't finished sending yet.
+    pending: HashSet<String>,
+}
+
+/// Recently sent messages. Each send appends a line to
+/// `<data_dir>/sent_messages.jsonl`; once the log holds twice `CAPACITY`
+/// lines it is rewritten with just the remembered ones.
+pub struct Messages {
+    inner: Mutex<Inner>,
+}
+
+impl Messages {
+    pub fn load(data_dir: &str) -> anyhow::Result<Self> {
+        let path = PathBuf::from_str(data_dir)
+            .unwrap()
+            .join("sent_messages.jsonl");
+        let (log, sent) = JsonLog::load(path)?;
+        let mut inner = Inner {
+            log,
+            sent: VecDeque::new(),
+            ids: HashSet::new(),
+            pending: HashSet::new(),
+        };
+        for message in sent {
+            inner.remember(message);
+        }
+        Ok(Self {
+            inner: Mutex::new(inner),
+        })
+    }
+
+    /// Reserve `id` for a send that is about to start. Returns `false` if a
+    /// message with this ID was already sent or is being sent.
+    pub async fn claim(&self, id: &str) -> bool {
+        let mut inner = self.inner.lock().await;
+        if inner.ids.contains(id) {
+            return false;
+        }
+        inner.pending.insert(id.to_string())
+    }
+
+    /// Drop a claim whose send didn't go through, so the ID can be retried.
+    pub async fn release(&self, id: &str) {
+        self.inner.lock().await.pending.remove(id);
+    }
+
+    pub async fn record(&self, id: &str, participants: &[String]) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().await;
+        inner.pending.remove(id);
+        if inner.ids.contains(id) {
+            return Ok(());
+        }
+        let message = SentMessage {
+            id: id.to_string(),
+            participants: participants.to_vec(),
+            sent_at: now_secs(),
+        };
+        inner.remember(message.clone());
+        if inner.log.lines() + 1 >= 2 * CAPACITY {
+            let Inner { log, sent, .. } = &mut *inner;
+            log.rewrite(sent.iter()).await
+        } else {
+            inner.log.append(&message).await
+        }
+    }
+}
+
+impl Inner {
+    fn remember(&mut self, message: SentMessage) {
+        if !self.ids.insert(message.id.clone()) {
+            return;
+        }
+        if self.sent.len() == CAPACITY {
+            if let Some(oldest) = self.sent.pop_front() {
+                self.ids.remove(&oldest.id);
+            }
+        }
+        self.sent.push_back(message);
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::chats::now_secs;
+use crate::types::MetricsResponse;
+
+/// Upper bounds (inclusive, in milliseconds) of the latency histogram buckets.
+/// Anything slower lands in a final overflow bucket.
+const BUCKETS_MS: [u64; 11] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// How many slow sends to keep for `/api/admin/slow-sends`.
+const SLOW_SEND_CAPACITY: usize = 50;
+
+#[derive(Default, Clone)]
+struct Histogram {
+    buckets: [u64; BUCKETS_MS.len() + 1],
+    count: u64,
+    sum_ms: u64,
+    max_ms: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, elapsed: Duration) {
+        let ms = elapsed.as_millis() as u64;
+        let bucket = BUCKETS_MS
+            .iter()
+            .position(|&le| ms <= le)
+            .unwrap_or(BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_ms += ms;
+        self.max_ms = self.max_ms.max(ms);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            count: self.count,
+            sum_ms: self.sum_ms,
+            max_ms: self.max_ms,
+            buckets: self
+                .buckets
+                .iter()
+                .enumerate()
+                .map(|(i, &count)| HistogramBucket {
+                    le_ms: BUCKETS_MS.get(i).copied(),
+                    count,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct HistogramBucket {
+    /// Upper bound of the bucket; `null` for the overflow bucket.
+    pub le_ms: Option<u64>,
+    pub count: u64,
+}
+
+#[derive(Serialize)]
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub sum_ms: u64,
+    pub max_ms: u64,
+    pub buckets: Vec<HistogramBucket>,
+}
+
+/// A send that took longer than the slow-send threshold, failed, or only
+/// went through after its keys were refreshed.
+#[derive(Serialize, Clone)]
+pub struct SlowSend {
+    pub message_id: String,
+    pub participants: Vec<String>,
+    pub at: u64,
+    pub duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The recipients' keys had changed and were looked up again.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub keys_refreshed: bool,
+}
+
+/// Process-wide counters exposed on `/api/metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    slow_send_threshold: Duration,
+    endpoints: Mutex<BTreeMap<String, Histogram>>,
+    send_duration: Mutex<Histogram>,
+    slow_sends: Mutex<VecDeque<SlowSend>>,
+    key_lookups: AtomicU64,
+    key_lookup_targets: AtomicU64,
+    key_lookup_ms_total: AtomicU64,
+    key_lookup_ms_last: AtomicU64,
+    key_recoveries: AtomicU64,
+    send_retries: AtomicU64,
+    last_send_at: AtomicU64,
+    last_receive_at: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new(slow_send_threshold: Duration) -> Self {
+        Self {
+            slow_send_threshold,
+            ..Default::default()
+        }
+    }
+
+    pub fn slow_send_threshold(&self) -> Duration {
+        self.slow_send_threshold
+    }
+
+    pub fn record_request(&self, endpoint: String, elapsed: Duration) {
+        self.endpoints
+            .lock()
+            .unwrap()
+            .entry(endpoint)
+            .or_default()
+            .record(elapsed);
+    }
+
+    /// Record how long the rustpush send took. Sends that failed, needed a
+    /// key refresh or took at least the slow-send threshold are kept for
+    /// later inspection.
+    pub fn record_send_duration(
+        &self,
+        message_id: &str,
+        participants: &[String],
+        elapsed: Duration,
+        error: Option<String>,
+        keys_refreshed: bool,
+    ) {
+        self.send_duration.lock().unwrap().record(elapsed);
+
+        if error.is_none() && !keys_refreshed && elapsed < self.slow_send_threshold {
+            return;
+        }
+        let mut slow_sends = self.slow_sends.lock().unwrap();
+        if slow_sends.len() == SLOW_SEND_CAPACITY {
+            slow_sends.pop_front();
+        }
+        slow_sends.push_back(SlowSend {
+            message_id: message_id.to_string(),
+            participants: participants.to_vec(),
+            at: now_secs(),
+            duration_ms: elapsed.as_millis() as u64,
+            error,
+            keys_refreshed,
+        });
+    }
+
+    /// Recent slow or failed sends, slowest first.
+    pub fn slow_sends(&self) -> Vec<SlowSend> {
+        let mut slow_sends: Vec<SlowSend> =
+            self.slow_sends.lock().unwrap().iter().cloned().collect();
+        slow_sends.sort_by(|a, b| b.duration_ms.cmp(&a.duration_ms));
+        slow_sends
+    }
+
+    pub fn record_key_lookup(&self, targets: usize, elapsed: Duration) {
+        let ms = elapsed.as_millis() as u64;
+        self.key_lookups.fetch_add(1, Ordering::Relaxed);
+        self.key_lookup_targets
+            .fetch_add(targets as u64, Ordering::Relaxed);
+        self.key_lookup_ms_total.fetch_add(ms, Ordering::Relaxed);
+        self.key_lookup_ms_last.store(ms, Ordering::Relaxed);
+    }
+
+    /// A send that failed on a missing recipient key went through after the
+    /// keys were refreshed.
+    pub fn record_key_recovery(&self) {
+        self.key_recoveries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_send_retry(&self) {
+        self.send_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_send(&self) {
+        self.last_send_at.store(now_secs(), Ordering::Relaxed);
+    }
+
+    pub fn record_receive(&self) {
+        self.last_receive_at.store(now_secs(), Ordering::Relaxed);
+    }
+
+    /// Unix timestamps of the last successful send and the last received
+    /// message, if any.
+    pub fn last_activity(&self) -> (Option<u64>, Option<u64>) {
+        let nonzero = |v: u64| (v != 0).then_some(v);
+        (
+            nonzero(self.last_send_at.load(Ordering::Relaxed)),
+            nonzero(self.last_receive_at.load(Ordering::Relaxed)),
+        )
+    }
+
+    pub fn snapshot(&self) -> MetricsResponse {
+        MetricsResponse {
+            key_lookups: self.key_lookups.load(Ordering::Relaxed),
+            key_lookup_targets: self.key_lookup_targets.load(Ordering::Relaxed),
+            key_lookup_ms_total: self.key_lookup_ms_total.load(Ordering::Relaxed),
+            key_lookup_ms_last: self.key_lookup_ms_last.load(Ordering::Relaxed),
+            key_recoveries: self.key_recoveries.load(Ordering::Relaxed),
+            send_retries: self.send_retries.load(Ordering::Relaxed),
+            send_duration: self.send_duration.lock().unwrap().snapshot(),
+            endpoints: self
+                .endpoints
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(endpoint, histogram)| (endpoint.clone(), histogram.snapshot()))
+                .collect(),
+        }
+    }
+}
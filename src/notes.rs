@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::chats::now_secs;
+use crate::persist;
+
+/// A private operator note. Notes are never sent to the recipient.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Note {
+    pub id: String,
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    pub created_at: u64,
+}
+
+impl Note {
+    pub fn new(text: String, author: Option<String>) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string().to_uppercase(),
+            text,
+            author,
+            created_at: now_secs(),
+        }
+    }
+}
+
+/// Notes attached to individual messages, keyed by message ID. Persisted to
+/// `<data_dir>/message_notes.json`. Chat notes live on the chat itself.
+pub struct MessageNotes {
+    path: PathBuf,
+    notes: Mutex<HashMap<String, Vec<Note>>>,
+}
+
+impl MessageNotes {
+    pub fn load(data_dir: &str) -> anyhow::Result<Self> {
+        let path = PathBuf::from_str(data_dir)
+            .unwrap()
+            .join("message_notes.json");
+        let notes = persist::load_json(&path)?;
+        Ok(Self {
+            path,
+            notes: Mutex::new(notes),
+        })
+    }
+
+    pub async fn get(&self, message_id: &str) -> Vec<Note> {
+        self.notes
+            .lock()
+            .await
+            .get(message_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub async fn add(&self, message_id: &str, note: Note) -> anyhow::Result<()> {
+        let mut notes = self.notes.lock().await;
+        notes.entry(message_id.to_string()).or_default().push(note);
+        self.save(&notes).await
+    }
+
+    pub async fn remove(&self, message_id: &str, note_id: &str) -> anyhow::Result<bool> {
+        let mut notes = self.notes.lock().await;
+        let Some(message_notes) = notes.get_mut(message_id) else {
+            return Ok(false);
+        };
+        let before = message_notes.len();
+        message_notes.retain(|n| n.id != note_id);
+        if message_notes.len() == before {
+            return Ok(false);
+        }
+        if message_notes.is_empty() {
+            notes.remove(message_id);
+        }
+        self.save(&notes).await?;
+        Ok(true)
+    }
+
+    async fn save(&self, notes: &HashMap<String, Vec<Note>>) -> anyhow::Result<()> {
+        persist::write_atomic(&self.path, &serde_json::to_vec(notes)?).await
+    }
+}
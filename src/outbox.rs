@@ -0,0 +1,205 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustpush::PushError;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Notify};
+
+use crate::backoff;
+use crate::chats::now_secs;
+use crate::handlers::AppState;
+use crate::persist;
+use crate::send::{self, SendOptions};
+use crate::session::NoSession;
+
+/// How often queued sends are retried while the connection stays down.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QueuedSend {
+    pub message_id: String,
+    pub to: String,
+    pub message: String,
+    pub queued_at: u64,
+}
+
+/// The outbox already holds as many sends as it may.
+#[derive(Debug)]
+pub struct OutboxFull {
+    pub capacity: usize,
+}
+
+impl fmt::Display for OutboxFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Connection to Apple is down and {} sends are already queued; try again later",
+            self.capacity
+        )
+    }
+}
+
+impl std::error::Error for OutboxFull {}
+
+/// Sends accepted while the connection to Apple was down, waiting to go out.
+/// Persisted to `<data_dir>/outbox.json` so they survive a restart. Holds at
+/// most `capacity` sends, and drops any still waiting after `ttl`.
+pub struct Outbox {
+    path: PathBuf,
+    queue: Mutex<VecDeque<QueuedSend>>,
+    wake: Notify,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl Outbox {
+    pub fn load(data_dir: &str, capacity: usize, ttl: Duration) -> anyhow::Result<Self> {
+        let path = PathBuf::from_str(data_dir).unwrap().join("outbox.json");
+        let queue = persist::load_json(&path)?;
+        Ok(Self {
+            path,
+            queue: Mutex::new(queue),
+            wake: Notify::new(),
+            capacity,
+            ttl,
+        })
+    }
+
+    pub async fn push(&self, send: QueuedSend) -> anyhow::Result<()> {
+        let mut queue = self.queue.lock().await;
+        if queue.len() >= self.capacity {
+            return Err(OutboxFull {
+                capacity: self.capacity,
+            }
+            .into());
+        }
+        queue.push_back(send);
+        self.save(&queue).await
+    }
+
+    pub async fn len(&self) -> usize {
+        self.queue.lock().await.len()
+    }
+
+    /// Retry queued sends now instead of at the next interval, e.g. after the
+    /// session was reloaded.
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    /// Drop sends that have waited longer than the TTL. Returns their IDs.
+    async fn expire(&self) -> anyhow::Result<Vec<String>> {
+        let mut queue = self.queue.lock().await;
+        let cutoff = now_secs().saturating_sub(self.ttl.as_secs());
+        let mut expired = vec![];
+        queue.retain(|send| {
+            let keep = send.queued_at >= cutoff;
+            if !keep {
+                log::warn!(
+                    "Dropping queued message {} to {}: not sent within {}s",
+                    send.message_id,
+                    send.to,
+                    self.ttl.as_secs()
+                );
+                expired.push(send.message_id.clone());
+            }
+            keep
+        });
+        if expired.is_empty() {
+            return Ok(expired);
+        }
+        self.save(&queue).await?;
+        Ok(expired)
+    }
+
+    async fn message_ids(&self) -> Vec<String> {
+        let queue = self.queue.lock().await;
+        queue.iter().map(|send| send.message_id.clone()).collect()
+    }
+
+    async fn front(&self) -> Option<QueuedSend> {
+        self.queue.lock().await.front().cloned()
+    }
+
+    async fn pop(&self) -> anyhow::Result<()> {
+        let mut queue = self.queue.lock().await;
+        queue.pop_front();
+        self.save(&queue).await
+    }
+
+    async fn save(&self, queue: &VecDeque<QueuedSend>) -> anyhow::Result<()> {
+        persist::write_atomic(&self.path, &serde_json::to_vec(queue)?).await
+    }
+}
+
+/// Whether a failed send should be queued for later rather than reported:
+/// the connection to Apple is down or the session is still being restored,
+/// not the message rejected. Once a restore has failed nothing goes out
+/// until the session is repaired, so sends fail instead of piling up.
+pub fn should_queue(state: &AppState, err: &anyhow::Error) -> bool {
+    if err.is::<NoSession>() {
+        return state.restore_error.read().unwrap().is_none();
+    }
+    retry_later(err)
+}
+
+/// Whether a queued send that failed with `err` should stay queued.
+fn retry_later(err: &anyhow::Error) -> bool {
+    err.is::<NoSession>()
+        || err
+            .downcast_ref::<PushError>()
+            .is_some_and(backoff::is_transient)
+}
+
+pub fn queued(message_id: String, to: &str, message: &str) -> QueuedSend {
+    QueuedSend {
+        message_id,
+        to: to.to_string(),
+        message: message.to_string(),
+        queued_at: now_secs(),
+    }
+}
+
+/// Drain the outbox in order. Stops at the first send that still can't get
+/// through and tries again after `RETRY_INTERVAL` or a wake-up. Sends that
+/// fail for any other reason, or expire, are dropped and their message IDs
+/// freed for reuse.
+pub async fn run(state: Arc<AppState>) {
+    // Queued IDs stay claimed across a restart, as they were when accepted
+    for id in state.outbox.message_ids().await {
+        state.messages.claim(&id).await;
+    }
+    loop {
+        match state.outbox.expire().await {
+            Ok(expired) => {
+                for id in expired {
+                    state.messages.release(&id).await;
+                }
+            }
+            Err(e) => log::warn!("Failed to save outbox: {}", e),
+        }
+        while let Some(queued) = state.outbox.front().await {
+            let options = SendOptions::with_id(queued.message_id.clone());
+            match send::send_text(&state, &queued.to, &queued.message, options).await {
+                Ok(_) => log::info!("Queued message {} sent", queued.message_id),
+                Err(e) if retry_later(&e) => break,
+                Err(e) => {
+                    log::warn!("Dropping queued message {}: {}", queued.message_id, e);
+                    state.messages.release(&queued.message_id).await;
+                }
+            }
+            if let Err(e) = state.outbox.pop().await {
+                log::warn!("Failed to save outbox: {}", e);
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(RETRY_INTERVAL) => {}
+            _ = state.outbox.wake.notified() => {}
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
+
+/// Read a JSON store. A missing file gives the default; one that exists but
+/// doesn't parse is an error, so a damaged store stops startup instead of
+/// loading as empty and being overwritten by the next save.
+pub fn load_json<T: DeserializeOwned + Default>(path: &Path) -> anyhow::Result<T> {
+    match std::fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).with_context(|| {
+            format!(
+                "{} is not valid JSON; repair or remove it to start fresh",
+                path.display()
+            )
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Replace `path` with `contents` so a crash leaves either the old or the
+/// new contents, never a truncated file: write a temporary file next to it,
+/// sync it and rename it over the original.
+pub async fn write_atomic(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    // Unique per write, so concurrent saves of one file can't interleave
+    static SEQ: AtomicU64 = AtomicU64::new(0);
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        SEQ.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp = PathBuf::from(tmp);
+
+    let result = async {
+        let mut file = tokio::fs::File::create(&tmp).await?;
+        file.write_all(contents).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp, path).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&tmp).await;
+    }
+    result.with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// An append-only JSON Lines file, one entry per line. Appends are cheap;
+/// owners call `rewrite` with just the live entries once the log has grown
+/// enough to be worth compacting.
+pub struct JsonLog {
+    path: PathBuf,
+    lines: usize,
+    /// Where a last line torn by a crash mid-append starts. It is cut off
+    /// before the next append, which would otherwise land after it and turn
+    /// it into a corrupt line in the middle of the file.
+    torn: Option<u64>,
+    /// The last line is complete but its newline never made it to disk.
+    unterminated: bool,
+}
+
+impl JsonLog {
+    /// Read the log without changing it. A missing file is empty; a torn
+    /// last line is skipped with a warning; any other line that doesn't
+    /// parse is an error.
+    pub fn load<T: DeserializeOwned>(path: PathBuf) -> anyhow::Result<(Self, Vec<T>)> {
+        let contents = match std::fs::read(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+
+        let mut entries = vec![];
+        let mut torn = None;
+        let mut offset = 0;
+        let mut lines = contents
+            .split_inclusive(|b| *b == b'\n')
+            .enumerate()
+            .peekable();
+        while let Some((i, line)) = lines.next() {
+            let start = offset;
+            offset += line.len();
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            match serde_json::from_slice(line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) if lines.peek().is_none() => {
+                    log::warn!("Ignoring incomplete last line of {}: {}", path.display(), e);
+                    torn = Some(start as u64);
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| {
+                        format!("{} line {} is not valid JSON", path.display(), i + 1)
+                    })
+                }
+            }
+        }
+
+        let log = Self {
+            lines: entries.len(),
+            unterminated: torn.is_none() && contents.last().is_some_and(|b| *b != b'\n'),
+            torn,
+            path,
+        };
+        Ok((log, entries))
+    }
+
+    /// Lines in the file, including ones whose entries are no longer live.
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    pub async fn append<T: Serialize>(&mut self, entry: &T) -> anyhow::Result<()> {
+        let mut line = vec![];
+        if self.unterminated {
+            line.push(b'\n');
+        }
+        serde_json::to_writer(&mut line, entry)?;
+        line.push(b'\n');
+
+        let result = async {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await?;
+            if let Some(torn) = self.torn {
+                file.set_len(torn).await?;
+            }
+            file.write_all(&line).await
+        }
+        .await;
+        result.with_context(|| format!("Failed to append to {}", self.path.display()))?;
+        self.torn = None;
+        self.unterminated = false;
+        self.lines += 1;
+        Ok(())
+    }
+
+    /// Replace the log with `entries`, atomically.
+    pub async fn rewrite<'a, T: Serialize + 'a>(
+        &mut self,
+        entries: impl IntoIterator<Item = &'a T>,
+    ) -> anyhow::Result<()> {
+        let mut contents = vec![];
+        let mut lines = 0;
+        for entry in entries {
+            serde_json::to_writer(&mut contents, entry)?;
+            contents.push(b'\n');
+            lines += 1;
+        }
+        write_atomic(&self.path, &contents).await?;
+        self.lines = lines;
+        self.torn = None;
+        self.unterminated = false;
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Latest contents per file that haven't been written yet.
+    pending: Mutex<HashMap<PathBuf, Vec<u8>>>,
+    /// Held while a batch is written, so an older batch can't land after a
+    /// newer one.
+    writing: tokio::sync::Mutex<()>,
+    wake: Notify,
+}
+
+/// Writes session state to disk from a background task, so the rustpush
+/// callbacks that fire on every key or token update never block the
+/// runtime. Writes to the same file that pile up while one is in flight are
+/// coalesced into the latest contents.
+#[derive(Clone)]
+pub struct Writer {
+    inner: Arc<Inner>,
+}
+
+impl Writer {
+    pub fn spawn() -> Self {
+        let writer = Self {
+            inner: Arc::new(Inner::default()),
+        };
+        let inner = writer.inner.clone();
+        tokio::spawn(async move {
+            loop {
+                inner.wake.notified().await;
+                drain(&inner).await;
+            }
+        });
+        writer
+    }
+
+    /// Queue `contents` to be written to `path`, replacing anything still
+    /// queued for it. Safe to call from synchronous callbacks.
+    pub fn write(&self, path: PathBuf, contents: Vec<u8>) {
+        self.inner.pending.lock().unwrap().insert(path, contents);
+        self.inner.wake.notify_one();
+    }
+
+    /// Write everything queued so far. Runs once the server has stopped,
+    /// whether through the shutdown endpoint or a signal.
+    pub async fn flush(&self) {
+        drain(&self.inner).await;
+    }
+}
+
+async fn drain(inner: &Inner) {
+    let _writing = inner.writing.lock().await;
+    let batch = std::mem::take(&mut *inner.pending.lock().unwrap());
+    for (path, contents) in batch {
+        if let Err(e) = write_atomic(&path, &contents).await {
+            log::error!("{:#}", e);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use async_trait::async_trait;
+use rustpush::{Message, MessageInst};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Outbound,
+    Inbound,
+}
+
+/// The view of a message that middleware can inspect and change.
+///
+/// Rewriting `text` changes what is sent (outbound) or what downstream
+/// consumers see (inbound). `annotations` is free-form metadata for later
+/// middleware and consumers; it is never sent to Apple.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PipelineMessage {
+    pub direction: Direction,
+    pub id: String,
+    pub sender: Option<String>,
+    pub participants: Vec<String>,
+    /// GUID of the group chat, when the message belongs to one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_name: Option<String>,
+    pub text: Option<String>,
+    /// ID of the chat an inbound message belongs to, resolved before the
+    /// pipeline runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_id: Option<String>,
+    /// Whether an inbound message was sent by one of our own handles, i.e.
+    /// from another of the account's devices.
+    #[serde(default)]
+    pub from_me: bool,
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+}
+
+impl PipelineMessage {
+    pub fn from_inst(direction: Direction, msg: &MessageInst) -> Self {
+        let text = match &msg.message {
+            Message::Message(normal) => Some(normal.parts.raw_text()),
+            _ => None,
+        };
+        Self {
+            direction,
+            id: msg.id.clone(),
+            sender: msg.sender.clone(),
+            participants: msg
+                .conversation
+                .as_ref()
+                .map(|c| c.participants.clone())
+                .unwrap_or_default(),
+            group_id: msg
+                .conversation
+                .as_ref()
+                .and_then(|c| c.sender_guid.clone()),
+            group_name: msg.conversation.as_ref().and_then(|c| c.cv_name.clone()),
+            text,
+            chat_id: None,
+            from_me: false,
+            annotations: HashMap::new(),
+        }
+    }
+}
+
+pub enum Action {
+    Continue,
+    Block(String),
+    /// Continue, and answer the sender of an inbound message with this text.
+    Reply(String),
+}
+
+/// A message was stopped by a middleware.
+#[derive(Debug)]
+pub struct Blocked {
+    pub by: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Message blocked by {}: {}", self.by, self.reason)
+    }
+}
+
+impl std::error::Error for Blocked {}
+
+#[async_trait]
+pub trait MessageMiddleware: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn process(&self, message: &mut PipelineMessage) -> Action;
+}
+
+/// Middleware chain run on every outbound and inbound message, in the order
+/// the middleware was registered.
+#[derive(Default)]
+pub struct Pipeline {
+    middleware: Vec<Box<dyn MessageMiddleware>>,
+}
+
+impl Pipeline {
+    pub fn register(&mut self, middleware: impl MessageMiddleware + 'static) {
+        log::info!("Registered message middleware: {}", middleware.name());
+        self.middleware.push(Box::new(middleware));
+    }
+
+    /// Run the chain, returning the replies queued by middleware, or the
+    /// middleware that blocked the message.
+    pub async fn run(&self, message: &mut PipelineMessage) -> Result<Vec<String>, Blocked> {
+        let mut replies = vec![];
+        for middleware in &self.middleware {
+            match middleware.process(message).await {
+                Action::Continue => {}
+                Action::Reply(text) => replies.push(text),
+                Action::Block(reason) => {
+                    log::info!(
+                        "{:?} message {} blocked by {}: {}",
+                        message.direction,
+                        message.id,
+                        middleware.name(),
+                        reason
+                    );
+                    return Err(Blocked {
+                        by: middleware.name().to_string(),
+                        reason,
+                    });
+                }
+            }
+        }
+        Ok(replies)
+    }
+}
+
+/// Logs every message passing through the pipeline at debug level.
+pub struct MessageLogger;
+
+#[async_trait]
+impl MessageMiddleware for MessageLogger {
+    fn name(&self) -> &str {
+        "logger"
+    }
+
+    async fn process(&self, message: &mut PipelineMessage) -> Action {
+        log::debug!(
+            "{:?} message {} from {:?} to {:?}",
+            message.direction,
+            message.id,
+            message.sender,
+            message.participants
+        );
+        Action::Continue
+    }
+}
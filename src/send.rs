@@ -0,0 +1,509 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use log::info;
+use rustpush::{ConversationData, Message, MessageInst, MessageType, NormalMessage, PushError};
+use tokio::task::JoinHandle;
+
+use crate::backoff;
+use crate::handlers::{format_phone, AppState, InvalidRecipient};
+use crate::pipeline::{Direction, PipelineMessage};
+use crate::session;
+
+/// How many times a send is retried after a transient network or APS error,
+/// and the delay before the first retry (doubled, tripled, ... after that).
+const TRANSIENT_RETRIES: u32 = 2;
+const TRANSIENT_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// IDS has no iMessage registration for one or more recipients.
+#[derive(Debug)]
+pub struct RecipientNotIMessage {
+    pub handles: Vec<String>,
+}
+
+impl fmt::Display for RecipientNotIMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Not registered for iMessage: {}",
+            self.handles.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for RecipientNotIMessage {}
+
+/// Sandbox mode is on and a recipient isn't on the allowlist.
+#[derive(Debug)]
+pub struct RecipientNotAllowed {
+    pub handles: Vec<String>,
+}
+
+impl fmt::Display for RecipientNotAllowed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Recipient not on the sandbox allowlist: {}",
+            self.handles.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for RecipientNotAllowed {}
+
+/// Who a message goes to: the recipients (excluding ourselves) and, for
+/// replies into an existing group, the group's name and GUID.
+pub struct Target {
+    pub recipients: Vec<String>,
+    pub cv_name: Option<String>,
+    pub sender_guid: Option<String>,
+}
+
+impl Target {
+    pub fn handle(to: &str) -> Result<Self, InvalidRecipient> {
+        Ok(Self {
+            recipients: vec![format_phone(to)?],
+            cv_name: None,
+            sender_guid: None,
+        })
+    }
+
+    /// The conversation an inbound message arrived in.
+    pub fn reply_to(message: &PipelineMessage, own_handles: &[String]) -> Self {
+        let mut recipients: Vec<String> = message
+            .participants
+            .iter()
+            .filter(|p| !own_handles.contains(p))
+            .cloned()
+            .collect();
+        if recipients.is_empty() {
+            recipients.extend(message.sender.clone());
+        }
+        Self {
+            recipients,
+            cv_name: message.group_name.clone(),
+            sender_guid: message.group_id.clone(),
+        }
+    }
+}
+
+/// Per-send knobs beyond the message itself.
+#[derive(Default)]
+pub struct SendOptions {
+    /// Pins the GUID of the outgoing message so a retried send (e.g. a
+    /// resumed broadcast) reuses the same ID instead of creating a new one.
+    pub message_id: Option<String>,
+    /// Give up on the send after this long.
+    pub timeout: Option<Duration>,
+    /// Wait up to this long for Apple to confirm delivery before returning.
+    pub wait_delivered: Option<Duration>,
+    /// The recipients' keys were already looked up, e.g. in one batch for a
+    /// whole broadcast, so skip the per-send IDS query.
+    pub keys_resolved: bool,
+}
+
+/// A message Apple accepted.
+pub struct Sent {
+    pub message_id: String,
+    /// Apple confirmed delivery while the send waited for it. `false` when
+    /// the send didn't wait, or rustpush gave it nothing to wait on.
+    pub delivered: bool,
+}
+
+impl Sent {
+    fn unconfirmed(message_id: String) -> Self {
+        Self {
+            message_id,
+            delivered: false,
+        }
+    }
+}
+
+impl SendOptions {
+    pub fn with_id(message_id: String) -> Self {
+        Self {
+            message_id: Some(message_id),
+            ..Default::default()
+        }
+    }
+}
+
+/// The send didn't finish within its timeout and was cancelled. It may or may
+/// not have reached Apple before that.
+#[derive(Debug)]
+pub struct SendTimedOut {
+    pub message_id: String,
+    pub timeout: Duration,
+}
+
+impl fmt::Display for SendTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Send of {} timed out after {}ms",
+            self.message_id,
+            self.timeout.as_millis()
+        )
+    }
+}
+
+impl std::error::Error for SendTimedOut {}
+
+/// Apple reported that the message couldn't be delivered.
+#[derive(Debug)]
+pub struct DeliveryFailed {
+    pub message_id: String,
+    pub error: String,
+}
+
+impl fmt::Display for DeliveryFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Delivery of {} failed: {}", self.message_id, self.error)
+    }
+}
+
+impl std::error::Error for DeliveryFailed {}
+
+/// The message was sent but delivery wasn't confirmed in time.
+#[derive(Debug)]
+pub struct DeliveryTimedOut {
+    pub message_id: String,
+    pub timeout: Duration,
+}
+
+impl fmt::Display for DeliveryTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Message {} sent but not confirmed delivered within {}ms",
+            self.message_id,
+            self.timeout.as_millis()
+        )
+    }
+}
+
+impl std::error::Error for DeliveryTimedOut {}
+
+/// Send a plain text iMessage to a single recipient.
+pub async fn send_text(
+    state: &AppState,
+    to: &str,
+    text: &str,
+    options: SendOptions,
+) -> anyhow::Result<Sent> {
+    info!("Sending message to {}", to);
+    let normal = NormalMessage::new(text.to_string(), MessageType::IMessage);
+    send(
+        state,
+        Target::handle(to)?,
+        Message::Message(normal),
+        options,
+    )
+    .await
+}
+
+/// What a send would do, as worked out by `dry_run`.
+pub struct DryRun {
+    pub sender: String,
+    pub recipients: Vec<String>,
+    pub text: Option<String>,
+}
+
+/// Go through every step of `send` short of handing the message to Apple:
+/// backoff, IDS lookup and the outbound pipeline. Fails with the same error
+/// the real send would.
+pub async fn dry_run(state: &AppState, target: Target, message: Message) -> anyhow::Result<DryRun> {
+    let recipients = target.recipients.clone();
+    let (sender, _, outbound) = prepare(state, target, message, None, false).await?;
+    Ok(DryRun {
+        sender,
+        recipients,
+        text: outbound.text,
+    })
+}
+
+/// Run `message` through the outbound pipeline and send it to `target`.
+pub async fn send(
+    state: &AppState,
+    target: Target,
+    message: Message,
+    options: SendOptions,
+) -> anyhow::Result<Sent> {
+    let message_id = options
+        .message_id
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string().to_uppercase());
+    let Some(timeout) = options.timeout else {
+        return dispatch(state, target, message, message_id, &options).await;
+    };
+
+    // Dropping the future on timeout cancels the send wherever it got to
+    let participants = target.recipients.clone();
+    match tokio::time::timeout(
+        timeout,
+        dispatch(state, target, message, message_id.clone(), &options),
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(_) => {
+            state.metrics.record_send_duration(
+                &message_id,
+                &participants,
+                timeout,
+                Some("timed out".to_string()),
+                false,
+            );
+            // It may have gone out, so the ID can't be reused
+            if let Err(e) = state.messages.record(&message_id, &participants).await {
+                log::warn!("Failed to record sent message: {}", e);
+            }
+            Err(SendTimedOut {
+                message_id,
+                timeout,
+            }
+            .into())
+        }
+    }
+}
+
+async fn dispatch(
+    state: &AppState,
+    target: Target,
+    message: Message,
+    message_id: String,
+    options: &SendOptions,
+) -> anyhow::Result<Sent> {
+    let wait_delivered = options.wait_delivered;
+    let (sender, mut msg, outbound) = prepare(
+        state,
+        target,
+        message,
+        Some(message_id),
+        options.keys_resolved,
+    )
+    .await?;
+    let message_id = msg.id.clone();
+
+    let session = state.session()?;
+    let started = Instant::now();
+    let mut keys_refreshed = false;
+    let mut retries = 0;
+    let result = loop {
+        let result = session.client.send(&mut msg).await;
+        let retry = match &result {
+            Err(PushError::KeyNotFound(handle)) if !keys_refreshed => {
+                // A recipient's devices changed after their keys were looked
+                // up. Fetch fresh keys and retry once before giving up.
+                log::warn!(
+                    "Send of {} found no key for {}; refreshing keys and retrying",
+                    message_id,
+                    handle
+                );
+                keys_refreshed = true;
+                let recipients: Vec<String> = outbound
+                    .participants
+                    .iter()
+                    .filter(|p| **p != sender)
+                    .cloned()
+                    .collect();
+                if let Err(e) =
+                    session::lookup_keys(&session.client, &sender, &recipients, &state.metrics)
+                        .await
+                {
+                    log::warn!("Key refresh for {} failed: {}", message_id, e);
+                }
+                true
+            }
+            // Resending the same MessageInst keeps its GUID, so a retry after
+            // a send that did reach Apple is deduplicated by the recipient
+            Err(e) if retries < TRANSIENT_RETRIES && backoff::is_transient(e) => {
+                retries += 1;
+                log::warn!(
+                    "Send of {} failed transiently ({}); retry {}/{}",
+                    message_id,
+                    e,
+                    retries,
+                    TRANSIENT_RETRIES
+                );
+                state.metrics.record_send_retry();
+                tokio::time::sleep(TRANSIENT_RETRY_DELAY * retries).await;
+                true
+            }
+            _ => false,
+        };
+        if !retry {
+            break result;
+        }
+    };
+    if keys_refreshed && result.is_ok() {
+        info!("Send of {} recovered after key refresh", message_id);
+        state.metrics.record_key_recovery();
+    }
+    state.metrics.record_send_duration(
+        &message_id,
+        &outbound.participants,
+        started.elapsed(),
+        result.as_ref().err().map(|e| e.to_string()),
+        keys_refreshed,
+    );
+    let result = result.inspect_err(|e| state.backoff.observe(e))?;
+    state.backoff.succeeded();
+    state.metrics.record_send();
+
+    if let Err(e) = state.chats.touch(&outbound).await {
+        log::warn!("Failed to record chat activity: {}", e);
+    }
+    if let Err(e) = state
+        .messages
+        .record(&message_id, &outbound.participants)
+        .await
+    {
+        log::warn!("Failed to record sent message: {}", e);
+    }
+
+    let Some(mut handle) = result.handle else {
+        return Ok(Sent::unconfirmed(message_id));
+    };
+
+    if let Some(wait) = wait_delivered {
+        let waited = tokio::time::timeout(wait, &mut handle).await;
+        let error = match waited {
+            Ok(Ok(Ok(()))) => {
+                info!("Message {} delivered", message_id);
+                return Ok(Sent {
+                    message_id,
+                    delivered: true,
+                });
+            }
+            Ok(Ok(Err(e))) => e.to_string(),
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => {
+                // Keep watching in the background so the outcome still gets logged
+                log_delivery(message_id.clone(), handle);
+                return Err(DeliveryTimedOut {
+                    message_id,
+                    timeout: wait,
+                }
+                .into());
+            }
+        };
+        log::warn!("Message {} delivery error: {}", message_id, error);
+        return Err(DeliveryFailed { message_id, error }.into());
+    }
+
+    log_delivery(message_id.clone(), handle);
+    Ok(Sent::unconfirmed(message_id))
+}
+
+fn log_delivery<E: fmt::Display + Send + 'static>(
+    message_id: String,
+    handle: JoinHandle<Result<(), E>>,
+) {
+    tokio::spawn(async move {
+        match handle.await {
+            Ok(Ok(())) => info!("Message {} delivered", message_id),
+            Ok(Err(e)) => log::warn!("Message {} delivery error: {}", message_id, e),
+            Err(e) => log::warn!("Message {} join error: {}", message_id, e),
+        }
+    });
+}
+
+/// Everything before the message goes out: pick the sender, check the
+/// backoff, look up the recipients' keys unless `keys_resolved`, and run
+/// the outbound pipeline.
+async fn prepare(
+    state: &AppState,
+    target: Target,
+    message: Message,
+    message_id: Option<String>,
+    keys_resolved: bool,
+) -> anyhow::Result<(String, MessageInst, PipelineMessage)> {
+    // Before anything that needs a session, so a send that could never go
+    // out is refused even while there is none
+    if let Some(allowed) = &state.allowed_recipients {
+        let denied: Vec<String> = target
+            .recipients
+            .iter()
+            .filter(|r| !allowed.contains(*r))
+            .cloned()
+            .collect();
+        if !denied.is_empty() {
+            return Err(RecipientNotAllowed { handles: denied }.into());
+        }
+    }
+
+    let session = state.session()?;
+    let handles = session.client.identity.get_handles().await;
+    let sender = handles
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("No registered handles"))?
+        .clone();
+
+    info!("Sending to {:?} from {}", target.recipients, sender);
+
+    state.backoff.check()?;
+
+    // Resolve keys for every recipient up front in one batched IDS query
+    // instead of letting the send path resolve participants one by one.
+    if !keys_resolved {
+        let reachable = match session::lookup_keys(
+            &session.client,
+            &sender,
+            &target.recipients,
+            &state.metrics,
+        )
+        .await
+        {
+            Ok(reachable) => reachable,
+            Err(e) => {
+                state.backoff.observe(&e);
+                return Err(e.into());
+            }
+        };
+        let unreachable: Vec<String> = target
+            .recipients
+            .iter()
+            .filter(|r| !reachable.contains(r))
+            .cloned()
+            .collect();
+        if !unreachable.is_empty() {
+            return Err(RecipientNotIMessage {
+                handles: unreachable,
+            }
+            .into());
+        }
+    }
+
+    let mut participants = vec![sender.clone()];
+    participants.extend(target.recipients);
+
+    // Thread into the existing conversation instead of letting the
+    // recipient's devices start a new one
+    let (known_guid, after_guid) = state
+        .chats
+        .thread(&participants, target.sender_guid.as_deref())
+        .await;
+    let conversation = ConversationData {
+        participants,
+        cv_name: target.cv_name,
+        sender_guid: target.sender_guid.or(known_guid),
+        after_guid,
+    };
+
+    let mut msg = MessageInst::new(conversation, &sender, message);
+    if let Some(id) = message_id {
+        msg.id = id;
+    }
+
+    let mut outbound = PipelineMessage::from_inst(Direction::Outbound, &msg);
+    let original = outbound.text.clone();
+    // Replies only make sense for inbound messages
+    state.pipeline.run(&mut outbound).await?;
+    if outbound.text != original {
+        if let Some(rewritten) = outbound.text.clone() {
+            msg.message = Message::Message(NormalMessage::new(rewritten, MessageType::IMessage));
+        }
+    }
+
+    Ok((sender, msg, outbound))
+}
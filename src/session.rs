@@ -1,8 +1,10 @@
+use std::fmt;
 use std::io::Cursor;
 use std::ops::Deref;
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, Once};
+use std::time::Instant;
 
 use keystore::software::{SoftwareEncryptor, SoftwareKeystore};
 use keystore::{
@@ -13,11 +15,16 @@ use log::info;
 use plist::{Data, Dictionary, Value};
 use rustpush::{
     default_provider, APSConnection, APSConnectionResource, APSMessage, APSState,
-    ArcAnisetteClient, DefaultAnisetteProvider, IDSNGMIdentity, IDSUser, IMClient,
-    LoginClientInfo, MADRID_SERVICE,
+    ArcAnisetteClient, DefaultAnisetteProvider, IDSNGMIdentity, IDSService, IDSUser,
+    IMClient, LoginClientInfo, MADRID_SERVICE,
 };
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, watch};
+
+use crate::handlers::AppState;
+use crate::inbound;
+use crate::metrics::Metrics;
+use crate::persist::Writer;
 
 use rustpush::macos::MacOSConfig;
 use rustpush::RelayConfig;
@@ -261,6 +268,52 @@ pub fn restore_users(path: &str) -> Option<Vec<IDSUser>> {
     plist::from_file::<_, Vec<IDSUser>>(&id_path).ok()
 }
 
+/// When an iMessage registration in `id.plist` was made and when IDS
+/// expects it to be renewed.
+pub struct Registration {
+    pub handles: Vec<String>,
+    pub registered_at: Option<u64>,
+    pub expires_at: Option<u64>,
+}
+
+/// Read the iMessage registrations straight from `id.plist`; IMClient doesn't
+/// expose registration timing.
+pub fn read_registrations(path: &str) -> Vec<Registration> {
+    let id_path = PathBuf::from_str(path).unwrap().join("id.plist");
+    let Ok(Value::Array(users)) = plist::from_file::<_, Value>(&id_path) else {
+        return vec![];
+    };
+    users
+        .iter()
+        .filter_map(|user| {
+            let registration = user
+                .as_dictionary()?
+                .get("registration")?
+                .as_dictionary()?
+                .get(MADRID_SERVICE.name)?
+                .as_dictionary()?;
+            let handles = registration
+                .get("handles")?
+                .as_array()?
+                .iter()
+                .filter_map(|h| h.as_string().map(str::to_string))
+                .collect();
+            let registered_at = registration
+                .get("registered_at_s")
+                .and_then(Value::as_unsigned_integer)
+                .filter(|&t| t != 0);
+            let heartbeat = registration
+                .get("heartbeat_interval_s")
+                .and_then(Value::as_unsigned_integer);
+            Some(Registration {
+                handles,
+                registered_at,
+                expires_at: registered_at.zip(heartbeat).map(|(at, hb)| at + hb),
+            })
+        })
+        .collect()
+}
+
 async fn get_login_config(
     conf_dir: &PathBuf,
     conf: &JoinedOSConfig,
@@ -302,6 +355,8 @@ pub async fn make_imclient(
     conn: &APSConnection,
     users: &Vec<IDSUser>,
     identity: &IDSNGMIdentity,
+    services: &[&'static IDSService],
+    writer: &Writer,
 ) -> Arc<IMClient> {
     let dir = PathBuf::from_str(path).unwrap();
     let id_path = dir.join("id.plist");
@@ -315,17 +370,19 @@ pub async fn make_imclient(
         let _ = std::fs::File::create(incident_path);
     }
 
+    let writer = writer.clone();
     Arc::new(
         IMClient::new(
             conn.clone(),
             users.clone(),
             identity.clone(),
-            &[&MADRID_SERVICE],
+            services,
             dir.join("id_cache.plist"),
             conn.os_config.clone(),
             Box::new(move |updated_keys| {
                 info!("updated keys");
-                std::fs::write(&id_path, plist_to_string(&updated_keys).unwrap()).unwrap();
+                let contents = plist_to_string(&updated_keys).unwrap();
+                writer.write(id_path.clone(), contents.into_bytes());
             }),
         )
         .await,
@@ -337,6 +394,7 @@ pub async fn setup_push(
     identity: &IDSNGMIdentity,
     state: Option<APSState>,
     state_path: &str,
+    writer: &Writer,
 ) -> (APSConnection, Option<rustpush::PushError>) {
     let state_path = PathBuf::from_str(state_path)
         .unwrap()
@@ -350,12 +408,14 @@ pub async fn setup_push(
             os_config: config.clone(),
             identity: saved_identity.clone().into(),
         };
-        std::fs::write(&state_path, plist_to_string(&state).unwrap()).unwrap();
+        let contents = plist_to_string(&state).unwrap();
+        writer.write(state_path.clone(), contents.into_bytes());
     }
 
     let mut to_refresh = conn.generated_signal.subscribe();
     let reconn_conn = Arc::downgrade(&conn);
     let config_ref = config.clone();
+    let writer = writer.clone();
     tokio::spawn(async move {
         loop {
             match to_refresh.recv().await {
@@ -368,7 +428,8 @@ pub async fn setup_push(
                         os_config: config_ref.clone(),
                         identity: saved_identity.clone().into(),
                     };
-                    std::fs::write(&state_path, plist_to_string(&state).unwrap()).unwrap();
+                    let contents = plist_to_string(&state).unwrap();
+                    writer.write(state_path.clone(), contents.into_bytes());
                 }
                 Err(broadcast::error::RecvError::Lagged(_)) => continue,
                 Err(broadcast::error::RecvError::Closed) => break,
@@ -410,21 +471,234 @@ pub async fn restore_account(
     Some(())
 }
 
+static KEYSTORE_INIT: Once = Once::new();
+
+/// `keystore.plist` as the installed keystore last read or wrote it, to
+/// notice when the file is replaced behind its back.
+static KEYSTORE_CONTENTS: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+
+/// The key OpenBubbles encrypts `keystore.plist` with on desktop.
+const KEYSTORE_SECRET: [u8; 32] = *b"desktopisinsecureyoushouldn'tber";
+
+/// The files in the data dir a session is restored from, and which can be
+/// uploaded through `/api/admin/session/files`.
+pub const SESSION_FILES: [&str; 5] = [
+    "hw_info.plist",
+    "id.plist",
+    "keystore.plist",
+    "gsa.plist",
+    "id_cache.plist",
+];
+
+/// Find the data dir when `IMESSAGE_DATA_DIR` isn't set: the OpenBubbles
+/// Flatpak install, then this app's own per-OS data directory (e.g.
+/// `~/.local/share/imessage-rest-api` on Linux). The first one holding a
+/// `hw_info.plist` wins. If none does, the per-OS directory is used anyway
+/// so `check` can report on it and session files can be uploaded there.
+pub fn default_data_dir() -> anyhow::Result<String> {
+    let mut candidates = vec![];
+    if let Some(base) = directories::BaseDirs::new() {
+        candidates.push(
+            base.home_dir()
+                .join(".var/app/app.openbubbles.OpenBubbles/data/bluebubbles"),
+        );
+    }
+    let own = directories::ProjectDirs::from("", "", "imessage-rest-api")
+        .map(|project| project.data_dir().to_path_buf());
+    candidates.extend(own.clone());
+
+    for dir in &candidates {
+        if dir.join("hw_info.plist").is_file() {
+            info!("Found session files in {}", dir.display());
+            return Ok(dir.to_string_lossy().into_owned());
+        }
+        info!("No session files in {}", dir.display());
+    }
+    let Some(own) = own else {
+        anyhow::bail!("Can't determine a data directory; set IMESSAGE_DATA_DIR");
+    };
+    log::warn!(
+        "No session files found; using {}. Set IMESSAGE_DATA_DIR to the OpenBubbles data directory, or upload the files there",
+        own.display()
+    );
+    Ok(own.to_string_lossy().into_owned())
+}
+
+/// A restored session: the IMClient and the APS connection it runs on.
+/// `/api/admin/reload-session` swaps in a fresh one without a restart.
+pub struct Session {
+    pub client: Arc<IMClient>,
+    pub conn: APSConnection,
+    /// The IDS services (push topics) the client was registered with.
+    pub topics: Vec<String>,
+    retired: watch::Sender<bool>,
+}
+
+impl Session {
+    pub fn new(client: Arc<IMClient>, conn: APSConnection, topics: Vec<String>) -> Self {
+        Self {
+            client,
+            conn,
+            topics,
+            retired: watch::channel(false).0,
+        }
+    }
+
+    /// Tell the APS pump and reader for this session to stop; it has been
+    /// replaced.
+    pub fn retire(&self) {
+        self.retired.send_replace(true);
+    }
+
+    pub fn is_retired(&self) -> bool {
+        *self.retired.borrow()
+    }
+
+    pub async fn retired(&self) {
+        let _ = self.retired.subscribe().wait_for(|&retired| retired).await;
+    }
+}
+
+/// There is no live session, because restore failed or hasn't finished.
+#[derive(Debug)]
+pub struct NoSession;
+
+impl fmt::Display for NoSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "No iMessage session is loaded; see session.restore_error on /api/status"
+        )
+    }
+}
+
+impl std::error::Error for NoSession {}
+
+/// `keystore.plist` was replaced after the keystore was installed, which
+/// only happens once per process.
+#[derive(Debug)]
+pub struct RestartRequired;
+
+impl fmt::Display for RestartRequired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "keystore.plist changed on disk since it was loaded; restart the server to use it"
+        )
+    }
+}
+
+impl std::error::Error for RestartRequired {}
+
+/// Restore the session from the data dir and make it the active one,
+/// retiring the previous session if there was one. If restore fails, the
+/// previous session stays active and the error is kept for `/api/status`.
+pub async fn activate(state: &Arc<AppState>) -> anyhow::Result<Arc<Session>> {
+    let _reloading = state.reloading.lock().await;
+    let services = state.topics.services();
+    let restored = restore(&state.data_dir, &services, &state.writer).await;
+    let (client, conn, aps_receiver) = match restored {
+        Ok(restored) => restored,
+        Err(e) => {
+            *state.restore_error.write().unwrap() = Some(format!("{:#}", e));
+            return Err(e);
+        }
+    };
+
+    let topics = services.iter().map(|s| s.name.to_string()).collect();
+    let session = Arc::new(Session::new(client, conn, topics));
+    let previous = state.session.write().unwrap().replace(session.clone());
+    *state.restore_error.write().unwrap() = None;
+    // Retire the old pump before the new one starts resuming the inbox,
+    // so it can't take on another message
+    if let Some(previous) = &previous {
+        previous.retire();
+    }
+    tokio::spawn(inbound::pump(state.clone(), session.clone(), aps_receiver));
+
+    if previous.is_none() {
+        // First session since startup: resume broadcasts that were
+        // interrupted by a crash or restart
+        for job in state.broadcasts.unfinished().await {
+            tokio::spawn(crate::broadcast::run(state.clone(), job));
+        }
+    }
+    state.outbox.wake();
+    Ok(session)
+}
+
+/// Load `keystore.plist` without ever writing it back, so `check` leaves the
+/// data dir untouched.
+pub fn load_keystore_readonly(path: &str) -> anyhow::Result<()> {
+    let keystore_path = PathBuf::from_str(path).unwrap().join("keystore.plist");
+    let state = plist::from_file(&keystore_path)?;
+    KEYSTORE_INIT.call_once(|| {
+        init_keystore(SoftwareKeystore {
+            state,
+            update_state: Box::new(|_| {}),
+            encryptor: SoftwareEncryptor(KEYSTORE_SECRET),
+        });
+    });
+    Ok(())
+}
+
+/// Decrypt the device identity in `hw_info.plist` with the loaded keystore.
+pub fn verify_identity(hardware: &SavedHardwareState) -> anyhow::Result<()> {
+    IDSNGMIdentity::restore(hardware.identity.as_ref(), "openbubbles")?;
+    Ok(())
+}
+
+/// Decrypt the Apple ID password in `gsa.plist` with the loaded keystore.
+/// Returns the Apple ID it belongs to.
+pub fn verify_account(path: &str) -> anyhow::Result<String> {
+    let dir = PathBuf::from_str(path).unwrap();
+    let state = plist::from_file::<_, GSAConfig>(&dir.join("gsa.plist"))?;
+    state.get_password()?;
+    Ok(state.username)
+}
+
 /// Restore the full session from Flatpak data directory.
 /// Returns (IMClient, APSConnection, sender_handle).
 pub async fn restore(
     path: &str,
+    services: &[&'static IDSService],
+    writer: &Writer,
 ) -> anyhow::Result<(Arc<IMClient>, APSConnection, broadcast::Receiver<APSMessage>)> {
     let dir = PathBuf::from_str(path).unwrap();
     let keystore_path = dir.join("keystore.plist");
 
-    init_keystore(SoftwareKeystore {
-        state: plist::from_file(&keystore_path).unwrap_or_default(),
-        update_state: Box::new(move |state| {
-            plist::to_file_xml(&keystore_path, state).unwrap();
-        }),
-        encryptor: SoftwareEncryptor(*b"desktopisinsecureyoushouldn'tber"),
+    // The keystore is process-global and can only be installed once; a
+    // session reload keeps the one loaded at startup, and refuses to run
+    // against a keystore.plist that has since been replaced
+    let mut installed = false;
+    KEYSTORE_INIT.call_once(|| {
+        installed = true;
+        let contents = std::fs::read(&keystore_path).ok();
+        let state = contents
+            .as_deref()
+            .and_then(|c| plist::from_bytes(c).ok())
+            .unwrap_or_default();
+        *KEYSTORE_CONTENTS.lock().unwrap() = contents;
+        let writer = writer.clone();
+        let keystore_path = keystore_path.clone();
+        init_keystore(SoftwareKeystore {
+            state,
+            update_state: Box::new(move |state| {
+                let contents = plist_to_string(state).unwrap().into_bytes();
+                *KEYSTORE_CONTENTS.lock().unwrap() = Some(contents.clone());
+                writer.write(keystore_path.clone(), contents);
+            }),
+            encryptor: SoftwareEncryptor(KEYSTORE_SECRET),
+        });
     });
+    if !installed {
+        // Our own pending writes would otherwise look like a replacement
+        writer.flush().await;
+        let on_disk = tokio::fs::read(&keystore_path).await.ok();
+        if on_disk != *KEYSTORE_CONTENTS.lock().unwrap() {
+            return Err(RestartRequired.into());
+        }
+    }
 
     if let Err(err) = std::panic::catch_unwind(|| {
         migrate(path);
@@ -444,13 +718,13 @@ pub async fn restore(
 
     info!("Setting up APS connection...");
     let (conn, push_err) =
-        setup_push(config, &identity, Some(hardware.push.clone()), path).await;
+        setup_push(config, &identity, Some(hardware.push.clone()), path, writer).await;
     if let Some(err) = push_err {
         log::warn!("Push setup warning: {}", err);
     }
 
     info!("Creating IMClient...");
-    let client = make_imclient(path, &conn, &users, &identity).await;
+    let client = make_imclient(path, &conn, &users, &identity, services, writer).await;
 
     info!("Setting up anisette...");
     let anisette = make_anisette(path, config, &conn).await;
@@ -463,3 +737,43 @@ pub async fn restore(
     info!("Session restored successfully");
     Ok((client, conn, aps_receiver))
 }
+
+/// Resolve IDS keys for all `targets` in a single lookup (rustpush splits it
+/// into as few IDS queries as the server allows) and record the timing.
+/// Returns the targets that are reachable over iMessage.
+pub async fn lookup_keys(
+    client: &IMClient,
+    sender: &str,
+    targets: &[String],
+    metrics: &Metrics,
+) -> Result<Vec<String>, rustpush::PushError> {
+    let started = Instant::now();
+    let result = client
+        .identity
+        .validate_targets(targets, MADRID_SERVICE.name, sender)
+        .await;
+    let elapsed = started.elapsed();
+    metrics.record_key_lookup(targets.len(), elapsed);
+    log::debug!("IDS lookup for {} targets took {:?}", targets.len(), elapsed);
+    result
+}
+
+/// Pre-fetch IDS keys for the given recipients so the first send after a
+/// restart doesn't pay the lookup latency.
+pub async fn warm_up(client: &IMClient, targets: &[String], metrics: &Metrics) {
+    let handles = client.identity.get_handles().await;
+    let Some(sender) = handles.first() else {
+        log::warn!("Skipping IDS warm-up: no registered handles");
+        return;
+    };
+
+    info!("Warming up IDS keys for {} recipients...", targets.len());
+    match lookup_keys(client, sender, targets, metrics).await {
+        Ok(valid) => info!(
+            "IDS warm-up done: {}/{} recipients on iMessage",
+            valid.len(),
+            targets.len()
+        ),
+        Err(e) => log::warn!("IDS warm-up failed: {}", e),
+    }
+}
@@ -0,0 +1,83 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use rustpush::{IDSService, FACETIME_SERVICE, MADRID_SERVICE, VIDEO_SERVICE};
+
+use crate::persist;
+
+/// Services that can be registered next to iMessage, by the name used in
+/// `IMESSAGE_OPTIONAL_TOPICS` and `/api/admin/topics`.
+pub const OPTIONAL_TOPICS: [&str; 1] = ["facetime"];
+
+static FACETIME: [&IDSService; 2] = [&FACETIME_SERVICE, &VIDEO_SERVICE];
+
+fn optional_services(name: &str) -> &'static [&'static IDSService] {
+    match name {
+        "facetime" => &FACETIME,
+        _ => &[],
+    }
+}
+
+/// Which optional push topics the session subscribes to. Starts from
+/// `IMESSAGE_OPTIONAL_TOPICS`; once changed through the API, the choice is
+/// persisted to `<data_dir>/topics.json` and takes precedence.
+pub struct Topics {
+    path: PathBuf,
+    enabled: RwLock<Vec<String>>,
+}
+
+impl Topics {
+    pub fn load(data_dir: &str, default: Vec<String>) -> anyhow::Result<Self> {
+        let path = PathBuf::from_str(data_dir).unwrap().join("topics.json");
+        let enabled = persist::load_json::<Option<Vec<String>>>(&path)?.unwrap_or(default);
+        validate(&enabled)?;
+        Ok(Self {
+            path,
+            enabled: RwLock::new(enabled),
+        })
+    }
+
+    pub fn enabled(&self) -> Vec<String> {
+        self.enabled.read().unwrap().clone()
+    }
+
+    /// Change the topics in memory and return the previous ones. Takes
+    /// effect with the next session restore; call `save` once that worked,
+    /// or put the previous topics back if it didn't.
+    pub fn replace(&self, enabled: Vec<String>) -> anyhow::Result<Vec<String>> {
+        validate(&enabled)?;
+        Ok(std::mem::replace(
+            &mut *self.enabled.write().unwrap(),
+            enabled,
+        ))
+    }
+
+    pub async fn save(&self) -> anyhow::Result<()> {
+        let enabled = self.enabled();
+        persist::write_atomic(&self.path, &serde_json::to_vec(&enabled)?).await
+    }
+
+    /// The IDS services to register: iMessage plus every enabled optional
+    /// one.
+    pub fn services(&self) -> Vec<&'static IDSService> {
+        let mut services = vec![&MADRID_SERVICE];
+        for name in self.enabled.read().unwrap().iter() {
+            services.extend(optional_services(name));
+        }
+        services
+    }
+}
+
+pub fn validate(names: &[String]) -> anyhow::Result<()> {
+    for name in names {
+        if !OPTIONAL_TOPICS.contains(&name.as_str()) {
+            anyhow::bail!(
+                "Unknown topic {:?}; available: {}",
+                name,
+                OPTIONAL_TOPICS.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
@@ -1,20 +1,132 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
+use crate::autoresponder::{MatchType, Rule, RuleAction};
+use crate::broadcast::BroadcastRecipient;
+use crate::chats::Chat;
+use crate::metrics::{HistogramSnapshot, SlowSend};
+use crate::notes::Note;
+
 #[derive(Deserialize)]
 pub struct SendRequest {
     pub to: String,
     pub message: String,
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Cancel the send and return 504 if it takes longer than this.
+    pub timeout_ms: Option<u64>,
+    /// Use this UUID as the message ID instead of generating one.
+    pub message_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SendQuery {
+    /// `delivered` to hold the response until Apple confirms delivery.
+    pub wait: Option<String>,
+    /// How long to wait, e.g. `10s` or `500ms`.
+    pub timeout: Option<String>,
 }
 
 #[derive(Serialize)]
 pub struct SendResponse {
     pub success: bool,
     pub message_id: String,
+    /// Set when the request waited for delivery: whether a delivery receipt
+    /// actually arrived.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delivered: Option<bool>,
+    /// The connection to Apple was down; the message will be sent once it's
+    /// back.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub queued: bool,
+}
+
+#[derive(Deserialize)]
+pub struct IndividualSendRequest {
+    pub to: Vec<String>,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+pub struct IndividualSendResult {
+    pub to: String,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct IndividualSendResponse {
+    pub sent: usize,
+    pub failed: usize,
+    pub results: Vec<IndividualSendResult>,
+}
+
+#[derive(Serialize)]
+pub struct DryRunResponse {
+    pub dry_run: bool,
+    pub sender: String,
+    pub recipients: Vec<String>,
+    /// The text as it would go out, after the outbound pipeline.
+    pub message: Option<String>,
 }
 
 #[derive(Serialize)]
 pub struct HandlesResponse {
     pub handles: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<HandleDetails>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HandleKind {
+    Phone,
+    Email,
+}
+
+#[derive(Serialize)]
+pub struct ChatParticipant {
+    pub handle: String,
+    pub kind: HandleKind,
+    /// One of this server's own handles.
+    pub own: bool,
+    /// Whether the handle can receive iMessages; `None` if that couldn't be
+    /// looked up, e.g. without a session.
+    pub imessage: Option<bool>,
+    /// When they last sent a message in this chat.
+    pub last_active: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ParticipantsResponse {
+    pub chat_id: String,
+    pub participants: Vec<ChatParticipant>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationState {
+    Registered,
+    /// Past the time IDS expected it to be renewed.
+    Expired,
+    /// Not found in the saved registration data.
+    Unknown,
+}
+
+#[derive(Serialize)]
+pub struct HandleDetails {
+    pub handle: String,
+    #[serde(rename = "type")]
+    pub kind: HandleKind,
+    pub registration: RegistrationState,
+    pub registered_at: Option<u64>,
+    pub expires_at: Option<u64>,
+    /// Whether sends go out from this handle.
+    pub default: bool,
 }
 
 #[derive(Serialize)]
@@ -22,3 +134,212 @@ pub struct HealthResponse {
     pub status: String,
 }
 
+/// A log filter in `RUST_LOG` syntax, e.g. `info,rustpush=debug`.
+#[derive(Serialize, Deserialize)]
+pub struct LogLevel {
+    pub filter: String,
+}
+
+/// A made-up incoming message for `/debug/inject`.
+#[derive(Deserialize)]
+pub struct InjectRequest {
+    /// Defaults to a fresh GUID; reuse one to test deduplication.
+    pub id: Option<String>,
+    pub sender: String,
+    /// Defaults to the sender and this server's first handle.
+    #[serde(default)]
+    pub participants: Vec<String>,
+    pub group_id: Option<String>,
+    pub group_name: Option<String>,
+    pub text: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct InjectResponse {
+    pub id: String,
+    /// `false` if a message with this GUID was already ingested.
+    pub processed: bool,
+}
+
+#[derive(Serialize)]
+pub struct RawMessageResponse {
+    pub id: String,
+    /// The decoded rustpush message, in its `Debug` format.
+    pub decoded: String,
+}
+
+#[derive(Deserialize)]
+pub struct ShutdownRequest {
+    #[serde(default)]
+    pub confirm: bool,
+}
+
+#[derive(Serialize)]
+pub struct MetricsResponse {
+    pub key_lookups: u64,
+    pub key_lookup_targets: u64,
+    pub key_lookup_ms_total: u64,
+    pub key_lookup_ms_last: u64,
+    /// Sends that succeeded on retry after refreshing a recipient's keys.
+    pub key_recoveries: u64,
+    /// Sends retried after a transient network or APS error.
+    pub send_retries: u64,
+    pub send_duration: HistogramSnapshot,
+    pub endpoints: BTreeMap<String, HistogramSnapshot>,
+}
+
+#[derive(Deserialize)]
+pub struct BroadcastRequest {
+    pub recipients: Vec<String>,
+    pub message: String,
+    pub concurrency: Option<usize>,
+    pub interval_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct BroadcastCreatedResponse {
+    pub broadcast_id: String,
+    pub total: usize,
+}
+
+#[derive(Serialize)]
+pub struct BroadcastStatusResponse {
+    pub id: String,
+    pub total: usize,
+    pub sent: usize,
+    pub failed: usize,
+    pub pending: usize,
+    pub done: bool,
+    pub recipients: Vec<BroadcastRecipient>,
+}
+
+#[derive(Deserialize)]
+pub struct NormalizeQuery {
+    pub to: String,
+}
+
+#[derive(Serialize)]
+pub struct NormalizeResponse {
+    pub input: String,
+    pub handle: String,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateRuleRequest {
+    pub match_type: MatchType,
+    pub pattern: String,
+    pub action: RuleAction,
+    pub enabled: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct RulesResponse {
+    pub rules: Vec<Rule>,
+    pub disabled_chats: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ChatToggleRequest {
+    pub enabled: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ChatsQuery {
+    pub label: Option<String>,
+    pub assignee: Option<String>,
+    #[serde(default)]
+    pub unassigned: bool,
+}
+
+#[derive(Serialize)]
+pub struct ChatsResponse {
+    pub chats: Vec<Chat>,
+}
+
+#[derive(Deserialize)]
+pub struct LabelsRequest {
+    pub labels: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AssigneeRequest {
+    pub assignee: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct NoteRequest {
+    pub text: String,
+    pub author: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct NotesResponse {
+    pub notes: Vec<Note>,
+}
+
+#[derive(Serialize)]
+pub struct StatusResponse {
+    pub version: &'static str,
+    pub uptime_secs: u64,
+    pub data_dir: String,
+    pub handles: Vec<String>,
+    pub session: SessionStatus,
+    pub aps: ApsStatus,
+    /// Seconds left on the global backoff after upstream errors, if active.
+    pub backoff_remaining_secs: Option<u64>,
+    pub last_send_at: Option<u64>,
+    pub last_receive_at: Option<u64>,
+    pub queues: QueueStatus,
+}
+
+#[derive(Serialize)]
+pub struct SessionStatus {
+    pub ready: bool,
+    /// Why the last session restore failed, if it did.
+    pub restore_error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct SessionFile {
+    pub name: String,
+    /// Size in bytes, or `null` if the file is missing.
+    pub size: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct SessionFilesResponse {
+    pub files: Vec<SessionFile>,
+}
+
+#[derive(Deserialize)]
+pub struct TopicsRequest {
+    pub enabled: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct TopicsResponse {
+    /// Topics the live session is registered for; empty without a session.
+    pub subscribed: Vec<String>,
+    /// Optional topics to register with the next session restore.
+    pub enabled: Vec<String>,
+    pub available: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct ApsStatus {
+    pub has_token: bool,
+}
+
+#[derive(Serialize)]
+pub struct QueueStatus {
+    pub broadcasts_running: usize,
+    pub broadcast_recipients_pending: usize,
+    pub outbox_pending: usize,
+}
+
+#[derive(Serialize)]
+pub struct SlowSendsResponse {
+    pub threshold_ms: u64,
+    pub sends: Vec<SlowSend>,
+}
@@ -0,0 +1,174 @@
+//! Message middleware implemented as WebAssembly plugins.
+//!
+//! A plugin is a core wasm module with no imports that exports:
+//!
+//! - `memory`
+//! - `alloc(len: i32) -> i32`: reserve `len` bytes and return a pointer
+//! - `transform(ptr: i32, len: i32) -> i64`: read the message JSON at
+//!   `ptr..ptr+len` and return the response JSON location packed as
+//!   `(ptr << 32) | len`
+//!
+//! The input is a `PipelineMessage` as JSON; the response is one of
+//! `{"action": "allow"}`, `{"action": "modify", "text": ..., "annotations": {...}}`,
+//! `{"action": "drop", "reason": ...}` or `{"action": "reply", "text": ...}`.
+//! Each call runs in a fresh instance with a fuel limit and a memory cap, so
+//! plugins keep no state between messages and cannot stall the server or
+//! exhaust its memory.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use serde::Deserialize;
+use wasmtime::{Config, Engine, Instance, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::pipeline::{Action, MessageMiddleware, PipelineMessage};
+
+const FUEL_PER_CALL: u64 = 50_000_000;
+
+/// Most linear memory a plugin instance may grow to.
+const MAX_MEMORY_BYTES: usize = 64 << 20;
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum PluginAction {
+    Allow,
+    Modify {
+        text: Option<String>,
+        #[serde(default)]
+        annotations: HashMap<String, String>,
+    },
+    Drop {
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    Reply {
+        text: String,
+    },
+}
+
+pub struct WasmPlugin {
+    name: String,
+    engine: Engine,
+    module: Module,
+}
+
+impl WasmPlugin {
+    pub fn load(engine: &Engine, path: &Path) -> anyhow::Result<Self> {
+        let name = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("wasm")
+            .to_string();
+        Ok(Self {
+            name: format!("wasm:{}", name),
+            engine: engine.clone(),
+            module: Module::from_file(engine, path)?,
+        })
+    }
+
+    fn call(engine: &Engine, module: &Module, input: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let limits: StoreLimits = StoreLimitsBuilder::new()
+            .memory_size(MAX_MEMORY_BYTES)
+            .build();
+        let mut store = Store::new(engine, limits);
+        store.limiter(|limits| limits);
+        store.set_fuel(FUEL_PER_CALL)?;
+        let instance = Instance::new(&mut store, module, &[])?;
+
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow!("plugin does not export memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let transform = instance.get_typed_func::<(i32, i32), i64>(&mut store, "transform")?;
+
+        let ptr = alloc.call(&mut store, input.len() as i32)?;
+        memory.write(&mut store, ptr as usize, input)?;
+        let packed = transform.call(&mut store, (ptr, input.len() as i32))? as u64;
+
+        let (out_ptr, out_len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        // Checked before allocating, so a bogus length can't make us reserve
+        // up to 4 GiB
+        if out_ptr
+            .checked_add(out_len)
+            .is_none_or(|end| end > memory.data_size(&store))
+        {
+            return Err(anyhow!(
+                "plugin returned {} bytes at {}, outside its memory",
+                out_len,
+                out_ptr
+            ));
+        }
+        let mut output = vec![0; out_len];
+        memory.read(&store, out_ptr, &mut output)?;
+        Ok(output)
+    }
+
+    async fn transform(&self, message: &PipelineMessage) -> anyhow::Result<PluginAction> {
+        let input = serde_json::to_vec(message)?;
+        let engine = self.engine.clone();
+        let module = self.module.clone();
+        let output =
+            tokio::task::spawn_blocking(move || Self::call(&engine, &module, &input)).await??;
+        Ok(serde_json::from_slice(&output)?)
+    }
+}
+
+#[async_trait]
+impl MessageMiddleware for WasmPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn process(&self, message: &mut PipelineMessage) -> Action {
+        // A broken plugin must not take messaging down with it: log and let
+        // the message through unchanged.
+        let action = match self.transform(message).await {
+            Ok(action) => action,
+            Err(e) => {
+                log::warn!(
+                    "Plugin {} failed on message {}: {}",
+                    self.name,
+                    message.id,
+                    e
+                );
+                return Action::Continue;
+            }
+        };
+
+        match action {
+            PluginAction::Allow => Action::Continue,
+            PluginAction::Modify { text, annotations } => {
+                if text.is_some() {
+                    message.text = text;
+                }
+                message.annotations.extend(annotations);
+                Action::Continue
+            }
+            PluginAction::Drop { reason } => {
+                Action::Block(reason.unwrap_or_else(|| "dropped by plugin".to_string()))
+            }
+            PluginAction::Reply { text } => Action::Reply(text),
+        }
+    }
+}
+
+/// Load every `*.wasm` file in `dir`, in file name order.
+pub fn load_plugins(dir: &str) -> anyhow::Result<Vec<WasmPlugin>> {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config)?;
+
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().and_then(|e| e.to_str()) == Some("wasm"))
+        .collect();
+    paths.sort();
+
+    paths
+        .iter()
+        .map(|path| WasmPlugin::load(&engine, path))
+        .collect()
+}